@@ -0,0 +1,135 @@
+/// `_MANIFEST.json` written by `unpack` next to the unpacked files.
+///
+///     {
+///       "format": 6,
+///       "preamble": {"unknown": 61440, "reserved": 0},
+///       "resources": [
+///         {"file": "8e47485e8c6ca138.lua", "flags": 0, "size": 1234, "languages": [0]},
+///         ...
+///       ]
+///     }
+///
+/// `repack` writes the bundle in the given format and preamble with resources
+/// in manifest order (resources not listed follow in the default order) and
+/// takes flags from the manifest over each file's index prefix. `size` and
+/// `languages` describe the bundle as unpacked and are not used for packing.
+/// Directories without a manifest pack the legacy way: format 6, default
+/// order and flags from the prefix.
+use std::fmt::Write;
+use std::io;
+
+use super::BundleFormat;
+use super::Layout;
+use super::LayoutEntry;
+use crate::hash;
+use crate::json;
+use crate::resource;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("manifest: {msg}"))
+}
+
+pub fn to_json(layout: &Layout) -> io::Result<String> {
+    let format = layout.format.version();
+    let mut out = String::new();
+    writeln!(out, "{{").unwrap();
+    writeln!(out, "  \"format\": {format},").unwrap();
+    writeln!(out, "  \"preamble\": {{\"unknown\": {}, \"reserved\": {}}},", layout.unknown, layout.reserved).unwrap();
+    writeln!(out, "  \"resources\": [").unwrap();
+    for (i, entry) in layout.resources.iter().enumerate() {
+        let (name_hash, ext_hash) = entry.file;
+        let ext = hash::extension_lookup(ext_hash)
+            .ok_or_else(|| invalid(&format!("unknown extension hash {ext_hash:016x}")))?;
+        let languages = entry.languages.iter()
+            .map(|language| language.to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        writeln!(out, "    {{\"file\": {}, \"flags\": {}, \"size\": {}, \"languages\": [{languages}]}}{}",
+            json::quote(&format!("{name_hash:016x}.{ext}")),
+            entry.flags,
+            entry.size,
+            if i + 1 < layout.resources.len() { "," } else { "" },
+        ).unwrap();
+    }
+    writeln!(out, "  ]").unwrap();
+    writeln!(out, "}}").unwrap();
+    Ok(out)
+}
+
+pub fn parse(data: &[u8]) -> io::Result<Layout> {
+    let value = json::parse(data)?;
+    let format = match value.get("format").and_then(|format| format.as_u64()) {
+        Some(6) => BundleFormat::Six,
+        Some(5) => BundleFormat::Five,
+        Some(4) => BundleFormat::Four,
+        _ => return Err(invalid("format must be 4, 5 or 6")),
+    };
+    let preamble = value.get("preamble");
+    let field = |name: &str| preamble
+        .and_then(|preamble| preamble.get(name))
+        .and_then(|value| value.as_u64());
+    let unknown = field("unknown")
+        .and_then(|unknown| u16::try_from(unknown).ok())
+        .ok_or_else(|| invalid("preamble.unknown must be a 16 bit number"))?;
+    let reserved = field("reserved")
+        .and_then(|reserved| u32::try_from(reserved).ok())
+        .ok_or_else(|| invalid("preamble.reserved must be a 32 bit number"))?;
+
+    let mut resources = Vec::new();
+    for resource in value.get("resources").and_then(|r| r.as_array()).unwrap_or_default() {
+        let file = resource.get("file").and_then(|file| file.as_str())
+            .ok_or_else(|| invalid("resource without file"))?;
+        let key = resource::parse_file_name(file)
+            .ok_or_else(|| invalid(&format!("\"{file}\" is not name.ext")))?;
+        let number = |name: &str| resource.get(name)
+            .and_then(|value| value.as_u64())
+            .and_then(|value| u32::try_from(value).ok());
+        let flags = number("flags")
+            .ok_or_else(|| invalid(&format!("flags of \"{file}\" must be a 32 bit number")))?;
+        let languages = resource.get("languages")
+            .and_then(|languages| languages.as_array())
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|language| language.as_u64().and_then(|language| u32::try_from(language).ok()))
+            .collect();
+        resources.push(LayoutEntry {
+            file: key,
+            flags,
+            size: number("size").unwrap_or(0),
+            languages,
+        });
+    }
+
+    Ok(Layout {
+        format,
+        unknown,
+        reserved,
+        resources,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let lua = hash::stingray_hash64(b"lua");
+        let layout = Layout {
+            format: BundleFormat::Five,
+            unknown: 0xf000,
+            reserved: 7,
+            resources: vec![
+                LayoutEntry { file: (2, lua), flags: 0, size: 10, languages: vec![0] },
+                LayoutEntry { file: (1, lua), flags: 1, size: 0, languages: vec![] },
+            ],
+        };
+        let parsed = parse(to_json(&layout).unwrap().as_bytes()).unwrap();
+        assert!(parsed.format == BundleFormat::Five);
+        assert_eq!((parsed.unknown, parsed.reserved), (0xf000, 7));
+        assert_eq!(parsed.resources.len(), 2);
+        assert_eq!(parsed.resources[0].file, (2, lua));
+        assert_eq!(parsed.resources[1].flags, 1);
+        assert_eq!(parsed.resources[0].languages, vec![0]);
+    }
+}
@@ -1,5 +1,7 @@
 use std::borrow::Cow;
 use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::sync::Arc;
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering;
 use std::sync::mpsc;
@@ -18,16 +20,31 @@ use byteorder::LE;
 use flate2::read;
 use flate2::write;
 use flate2::Compression;
+use crate::convert::Convert;
+use crate::select::Selector;
 use crate::hash;
+use crate::hash::MurmurHash64;
 
+mod manifest;
 mod pack;
 pub use pack::pack_dir_to_bundle;
+pub use pack::salvage_bundle_to_dir;
 pub use pack::unpack_bundle_to_dir;
+pub use pack::watch_dir_to_bundle;
+pub use pack::Conflict;
 pub use pack::Merge;
 
 #[derive(Clone)]
 pub struct PackSettings {
     pub num_threads: usize,
+    // resource kinds converted to plain files by unpack_bundle_to_dir
+    pub convert: Convert,
+    // reverse lookup for hashes (used to name converted strings keys)
+    pub dictionary: Option<Arc<HashMap<MurmurHash64, String>>>,
+    // resources written by unpack_bundle_to_dir
+    pub select: Selector,
+    // list SHA-256 of resources in unpacked directories
+    pub content_hash: bool,
 }
 
 pub trait IBundleUnpacker {
@@ -36,6 +53,10 @@ pub trait IBundleUnpacker {
     // file: (name_hash, ext_hash)
     fn write_file(&mut self, file: (u64, u64), data: &[u8]) -> io::Result<()>;
     fn write_header(&mut self, data: &[u8]) -> io::Result<()>;
+    // format, preamble and order of the unpacked bundle
+    fn write_layout(&mut self, _layout: &Layout) -> io::Result<()> {
+        Ok(())
+    }
 
     fn unpack(&mut self, settings: &PackSettings) -> io::Result<()>
     where
@@ -52,6 +73,10 @@ pub trait IBundlePacker {
     fn files(&self) -> io::Result<Box<dyn Iterator<Item = io::Result<(u64, u64)>> + '_>>;
     fn read_file(&self, file: (u64, u64)) -> io::Result<Cow<[u8]>>;
     fn read_header(&self) -> io::Result<Cow<[u8]>>;
+    // None packs format 6 in the default order
+    fn read_layout(&self) -> io::Result<Option<Layout>> {
+        Ok(None)
+    }
 
     fn repack(&mut self, settings: &PackSettings) -> io::Result<()>
     where
@@ -61,7 +86,7 @@ pub trait IBundlePacker {
     }
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, Copy, PartialEq)]
 pub enum BundleFormat {
     // VT2
     Six,
@@ -71,8 +96,39 @@ pub enum BundleFormat {
     Four,
 }
 
+impl BundleFormat {
+    pub fn version(self) -> u16 {
+        match self {
+            BundleFormat::Six => 6,
+            BundleFormat::Five => 5,
+            BundleFormat::Four => 4,
+        }
+    }
+}
+
+// Bundle as found when unpacking, kept in an unpacked directory's manifest.
+#[derive(Clone)]
+pub struct Layout {
+    pub format: BundleFormat,
+    // header fields following the version
+    pub unknown: u16,
+    pub reserved: u32,
+    // resources in bundle order
+    pub resources: Vec<LayoutEntry>,
+}
+
+#[derive(Clone)]
+pub struct LayoutEntry {
+    // (name_hash, ext_hash)
+    pub file: (u64, u64),
+    pub flags: u32,
+    // size as in srpack's index prefix
+    pub size: u32,
+    pub languages: Vec<u32>,
+}
+
 fn unpack_bundle(unpack: &mut dyn IBundleUnpacker, settings: &PackSettings) -> io::Result<()> {
-    let num_threads = settings.num_threads.min(1);
+    let num_threads = settings.num_threads.max(1);
     let (reader, size) = unpack.bundle_reader().unwrap();
     assert!(size > 16);
 
@@ -88,8 +144,9 @@ fn unpack_bundle(unpack: &mut dyn IBundleUnpacker, settings: &PackSettings) -> i
         f => unimplemented!("unsupported bundle format {f}"),
     };
 
-    let _unknown = header.read_u16::<LE>().unwrap();
+    let unknown = header.read_u16::<LE>().unwrap();
     let uncompressed_size = header.read_u32::<LE>().unwrap() as usize;
+    let reserved = header.read_u32::<LE>().unwrap();
     let mut inf_buffer = vec![0; uncompressed_size];
     let mut def_buffer = vec![0; size];
     let mut inf_chunks = inf_buffer.chunks_mut(0x10000);
@@ -98,13 +155,15 @@ fn unpack_bundle(unpack: &mut dyn IBundleUnpacker, settings: &PackSettings) -> i
     let running = AtomicBool::new(true);
     thread::scope(|s| {
         let upper = usize::try_from(uncompressed_size).unwrap() / (16 * 1024 * 1024);
-        for _ in 0..num_threads.min(upper).min(1) {
+        for _ in 0..num_threads.min(upper).max(1) {
             s.spawn(|| {
                 'pull: while let Some((in_chunk, buffer)) = {
+                    // load before locking so an empty queue after the last push ends the loop
+                    let running = running.load(Ordering::SeqCst);
                     let mut queue = queue.lock().unwrap();
                     if queue.len() == 0 {
                         drop(queue);
-                        if running.load(Ordering::SeqCst) {
+                        if running {
                             continue 'pull;
                         } else {
                             None
@@ -167,8 +226,6 @@ fn unpack_bundle(unpack: &mut dyn IBundleUnpacker, settings: &PackSettings) -> i
         running.store(false, Ordering::SeqCst);
     });
 
-    std::fs::write(r"C:\modding\bundle.test_raw", &inf_buffer).unwrap();
-
     let mut rdr = Cursor::new(&inf_buffer);
     let num_files = rdr.read_u32::<LE>().unwrap() as usize;
     unpack.write_header(&inf_buffer[4..260]).unwrap();
@@ -181,6 +238,12 @@ fn unpack_bundle(unpack: &mut dyn IBundleUnpacker, settings: &PackSettings) -> i
     };
     let mut offset = 260 + num_files * index_entry_size;
     let mut buffer = Vec::with_capacity(0x20000);
+    let mut layout = Layout {
+        format,
+        unknown,
+        reserved,
+        resources: Vec::with_capacity(num_files),
+    };
     for _ in 0..num_files {
         buffer.clear();
         let mut patch_size = false;
@@ -215,8 +278,9 @@ fn unpack_bundle(unpack: &mut dyn IBundleUnpacker, settings: &PackSettings) -> i
         let num_localizations = rdr.read_u32::<LE>().unwrap();
         let _unknown = rdr.read_u32::<LE>().unwrap();
         let mut size = 24;
+        let mut languages = Vec::with_capacity(num_localizations as usize);
         for _ in 0..num_localizations {
-            let _unknown = rdr.read_u32::<LE>().unwrap();
+            languages.push(rdr.read_u32::<LE>().unwrap());
             let local_len = rdr.read_u32::<LE>().unwrap();
             let _unknown = rdr.read_u32::<LE>().unwrap();
             if patch_size {
@@ -229,11 +293,214 @@ fn unpack_bundle(unpack: &mut dyn IBundleUnpacker, settings: &PackSettings) -> i
         offset += size;
         rdr.set_position(current);
         unpack.write_file((name_hash, ext_hash), &buffer).unwrap();
+        layout.resources.push(LayoutEntry {
+            file: (name_hash, ext_hash),
+            flags,
+            size: u32::from_le_bytes(buffer[20..24].try_into().unwrap()),
+            languages,
+        });
     }
+    unpack.write_layout(&layout)?;
 
     Ok(())
 }
 
+pub struct Salvage {
+    pub recovered: usize,
+    // (name_hash, ext_hash) of resources that could not be recovered
+    pub lost: Vec<(u64, u64)>,
+    // chunks missing or failing to inflate, out of chunks expected
+    pub bad_chunks: usize,
+    pub chunks: usize,
+}
+
+// Unpack every resource whose data is fully present in a damaged or
+// truncated bundle. After a damaged region, unpacking resumes at the next
+// resource whose header is found in readable data.
+fn salvage_bundle(unpack: &mut dyn IBundleUnpacker) -> io::Result<Salvage> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+    let (reader, _) = unpack.bundle_reader()?;
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data)?;
+    if data.len() < 12 {
+        return Err(invalid("bundle header is truncated"));
+    }
+
+    let mut header = &data[..12];
+    let format = match header.read_u16::<LE>()? {
+        6 => BundleFormat::Six,
+        5 => BundleFormat::Five,
+        4 => BundleFormat::Four,
+        _ => return Err(invalid("unsupported bundle format")),
+    };
+    let unknown = header.read_u16::<LE>()?;
+    let uncompressed_size = header.read_u32::<LE>()? as usize;
+    let reserved = header.read_u32::<LE>()?;
+
+    // grown by the chunks actually present, as a damaged header can claim
+    // any size
+    let chunks = uncompressed_size.div_ceil(0x10000);
+    let mut inf_buffer = Vec::new();
+    let mut valid = Vec::new();
+    let mut offset = 12;
+    while valid.len() < chunks {
+        let Some(size) = data.get(offset..offset + 4) else {
+            break;
+        };
+        let size = u32::from_le_bytes(size.try_into().unwrap()) as usize;
+        // chunks after one with a broken size can't be found
+        if size > 0x10000 || offset + 4 + size > data.len() {
+            break;
+        }
+        let in_chunk = &data[offset + 4..offset + 4 + size];
+        let start = inf_buffer.len();
+        inf_buffer.resize(uncompressed_size.min(start + 0x10000), 0);
+        let out_chunk = &mut inf_buffer[start..];
+        if size == 0x10000 {
+            out_chunk.copy_from_slice(&in_chunk[..out_chunk.len()]);
+            valid.push(true);
+        } else {
+            let mut e = read::ZlibDecoder::new(in_chunk);
+            valid.push(e.read_exact(out_chunk).is_ok());
+        }
+        offset += 4 + size;
+    }
+    let bad_chunks = chunks - valid.iter().filter(|v| **v).count();
+    valid.resize(chunks, false);
+
+    let present = |start: usize, end: usize| {
+        end <= inf_buffer.len() && (start / 0x10000..end.div_ceil(0x10000)).all(|chunk| valid[chunk])
+    };
+
+    let index_entry_size = match format {
+        BundleFormat::Six => 24,
+        BundleFormat::Five => 20,
+        BundleFormat::Four => 16,
+    };
+    if !present(0, 260) {
+        return Err(invalid("bundle index is not readable"));
+    }
+    let num_files = u32::from_le_bytes(inf_buffer[..4].try_into().unwrap()) as usize;
+    let index_end = 260 + num_files * index_entry_size;
+    if !present(0, index_end) {
+        return Err(invalid("bundle index is not readable"));
+    }
+    unpack.write_header(&inf_buffer[4..260])?;
+    let index = &inf_buffer[260..index_end];
+    let entry = |i: usize| &index[i * index_entry_size..(i + 1) * index_entry_size];
+    let key = |i: usize| (
+        u64::from_le_bytes(entry(i)[8..16].try_into().unwrap()),
+        u64::from_le_bytes(entry(i)[..8].try_into().unwrap()),
+    );
+
+    let mut recovered = 0;
+    let mut lost = Vec::new();
+    // lists recovered resources only
+    let mut layout = Layout {
+        format,
+        unknown,
+        reserved,
+        resources: Vec::new(),
+    };
+    // positions in index of each resource header, for resyncing
+    let mut positions = HashMap::<&[u8], Vec<usize>>::new();
+    for j in 0..num_files {
+        positions.entry(&entry(j)[..16]).or_default().push(j);
+    }
+    let mut offset = index_end;
+    let mut i = 0;
+    let mut buffer = Vec::with_capacity(0x20000);
+    while i < num_files {
+        let header = offset + 24 <= inf_buffer.len()
+            && present(offset, offset + 24)
+            && inf_buffer[offset..offset + 16] == entry(i)[..16];
+        let num_variants = if header {
+            u32::from_le_bytes(inf_buffer[offset + 16..offset + 20].try_into().unwrap()) as usize
+        } else {
+            0
+        };
+        let variants_end = offset + 24 + num_variants * 12;
+        if header && present(offset, variants_end) {
+            let lens = inf_buffer[offset + 24..variants_end].chunks_exact(12)
+                .map(|variant| u32::from_le_bytes(variant[4..8].try_into().unwrap()))
+                .collect::<Vec<_>>();
+            let size = variants_end - offset + lens.iter().map(|len| *len as usize).sum::<usize>();
+            if present(offset, offset + size) {
+                let entry = entry(i);
+                let (flags, file_size) = match format {
+                    BundleFormat::Six => (
+                        u32::from_le_bytes(entry[16..20].try_into().unwrap()),
+                        u32::from_le_bytes(entry[20..24].try_into().unwrap()),
+                    ),
+                    BundleFormat::Five => (
+                        u32::from_le_bytes(entry[16..20].try_into().unwrap()),
+                        lens.last().copied().unwrap_or(0),
+                    ),
+                    BundleFormat::Four => (0, lens.last().copied().unwrap_or(0)),
+                };
+                buffer.clear();
+                buffer.extend(&entry[..16]);
+                buffer.write_u32::<LE>(flags)?;
+                buffer.write_u32::<LE>(file_size)?;
+                buffer.extend(&inf_buffer[offset..offset + size]);
+                unpack.write_file(key(i), &buffer)?;
+                layout.resources.push(LayoutEntry {
+                    file: key(i),
+                    flags,
+                    size: file_size,
+                    languages: inf_buffer[offset + 24..variants_end].chunks_exact(12)
+                        .map(|variant| u32::from_le_bytes(variant[..4].try_into().unwrap()))
+                        .collect(),
+                });
+                recovered += 1;
+                offset += size;
+                i += 1;
+                continue;
+            }
+        }
+
+        // resync at the next resource header found in readable data
+        let start = (offset + 1).min(inf_buffer.len());
+        let next = inf_buffer[start..].windows(16)
+            .enumerate()
+            .find_map(|(pos, window)| {
+                let j = positions.get(window)?.iter().find(|j| **j > i)?;
+                present(start + pos, start + pos + 24).then_some((*j, start + pos))
+            });
+        match next {
+            Some((j, pos)) => {
+                lost.extend((i..j).map(key));
+                i = j;
+                offset = pos;
+            }
+            None => {
+                lost.extend((i..num_files).map(key));
+                break;
+            }
+        }
+    }
+    unpack.write_layout(&layout)?;
+
+    Ok(Salvage {
+        recovered,
+        lost,
+        bad_chunks,
+        chunks,
+    })
+}
+
+// Sets flag when dropped.
+struct Finish<'a>(&'a AtomicBool);
+
+impl Drop for Finish<'_> {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+// (name_hash, ext_hash, data) of files in the order they are packed
+type PackFiles<'a> = Box<dyn Iterator<Item = io::Result<(u64, u64, Cow<'a, [u8]>)>> + 'a>;
+
 fn repack_bundle(pack: &mut dyn IBundlePacker, settings: &PackSettings) -> io::Result<()> {
     let num_threads = settings.num_threads;
     let mut files = Vec::new();
@@ -242,7 +509,10 @@ fn repack_bundle(pack: &mut dyn IBundlePacker, settings: &PackSettings) -> io::R
         .unwrap_or(Cow::Borrowed(&[0; 256]))
         .to_vec();
 
-    for file in pack.files().unwrap() {
+    let layout = pack.read_layout()?;
+    let format = layout.as_ref().map(|layout| layout.format).unwrap_or(BundleFormat::Six);
+
+    for file in pack.files()? {
         let (name_hash, ext_hash) = file?;
         files.push((
             name_hash,
@@ -250,16 +520,30 @@ fn repack_bundle(pack: &mut dyn IBundlePacker, settings: &PackSettings) -> io::R
         ));
     }
 
+    // resources listed in the layout keep their order, others follow sorted
+    let order = layout.as_ref()
+        .map(|layout| layout.resources.iter()
+            .enumerate()
+            .map(|(i, entry)| (entry.file, i))
+            .collect::<HashMap<_, _>>())
+        .unwrap_or_default();
     files.sort_unstable_by(|(a_name, a_ext), (b_name, b_ext)|
-        (*a_ext as u32).cmp(&(*b_ext as u32))
+        order.get(&(*a_name, *a_ext)).unwrap_or(&usize::MAX)
+            .cmp(order.get(&(*b_name, *b_ext)).unwrap_or(&usize::MAX))
+            .then((*a_ext as u32).cmp(&(*b_ext as u32)))
             .then(a_name.cmp(&b_name))
     );
+    let index_entry_size = match format {
+        BundleFormat::Six => 24,
+        BundleFormat::Five => 20,
+        BundleFormat::Four => 16,
+    };
     let num_files = files.len();
 
     let finished = AtomicBool::new(false);
     let queue = Mutex::new(Vec::<(usize, Vec<u8>)>::with_capacity(num_files * 4));
     let (tx, rx) = mpsc::channel();
-    thread::scope(|s| {
+    thread::scope(|s| -> io::Result<()> {
         for _ in 0..num_threads {
             let tx = tx.clone();
             s.spawn(|| {
@@ -290,8 +574,10 @@ fn repack_bundle(pack: &mut dyn IBundlePacker, settings: &PackSettings) -> io::R
             });
         }
         drop(tx);
+        // stops the workers when returning early on errors
+        let _finish = Finish(&finished);
 
-        let entry_size = 260 + num_files * 24;
+        let entry_size = 260 + num_files * index_entry_size;
         total_size += entry_size;
         let first_offset = if entry_size % 0x10000 == 0 {
             0
@@ -311,14 +597,22 @@ fn repack_bundle(pack: &mut dyn IBundlePacker, settings: &PackSettings) -> io::R
         let mut first = Some(&mut entry_tail);
         let mut buffer = Vec::with_capacity(0x10000);
         let mut count = 0;
-        let mut iter: Box<dyn Iterator<Item = (u64, u64, Cow<[u8]>)>> =
-            Box::new(files.iter().map(|f| (f.0, f.1, pack.read_file((f.0, f.1)).unwrap())));
-        while let Some((name_hash, ext_hash, data)) = iter.next() {
+        let mut iter: PackFiles =
+            Box::new(files.iter().map(|f| pack.read_file((f.0, f.1)).map(|data| (f.0, f.1, data))));
+        while let Some(file) = iter.next() {
+            let (name_hash, ext_hash, data) = file?;
+            if data.len() < 24 {
+                let ext = hash::extension_lookup(ext_hash).unwrap_or("?");
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{name_hash:016x}.{ext} is too small for srpack's index prefix"),
+                ));
+            }
             total_size += data.len() - 24;
             count += 1;
 
             // check for deleted file (0x01) in packed header and sort to end
-            let flag = (&data[16..20]).read_u32::<LE>().unwrap();
+            let flag = (&data[16..20]).read_u32::<LE>()?;
             if (flag == 0x01 || flag == 0x02)
                 && let Some(ref mut removed) = removed_files
             {
@@ -330,11 +624,11 @@ fn repack_bundle(pack: &mut dyn IBundlePacker, settings: &PackSettings) -> io::R
                             .then(a_ext.cmp(&b_ext))
                             .then(a_name.cmp(&b_name))
                     );
-                    iter = Box::new(removed.into_iter().map(|(a, b, _, c)| (a, b, c)));
+                    iter = Box::new(removed.into_iter().map(|(a, b, _, c)| Ok((a, b, c))));
                 }
                 continue;
             }
-            entry.extend(&data[..24]);
+            entry.extend(&data[..index_entry_size]);
 
             if count == files.len() && removed_files.is_some() {
                 let mut removed = removed_files.take().unwrap();
@@ -343,7 +637,7 @@ fn repack_bundle(pack: &mut dyn IBundlePacker, settings: &PackSettings) -> io::R
                         .then(a_ext.cmp(&b_ext))
                         .then(a_name.cmp(&b_name))
                 );
-                iter = Box::new(removed.into_iter().map(|(a, b, _, c)| (a, b, c)));
+                iter = Box::new(removed.into_iter().map(|(a, b, _, c)| Ok((a, b, c))));
             }
 
             // ignore file's index header packed at start
@@ -402,11 +696,16 @@ fn repack_bundle(pack: &mut dyn IBundlePacker, settings: &PackSettings) -> io::R
         //test_file.write_all(&entry).unwrap();
         //test_file.write_all(&test_raw).unwrap();
 
-        let writer = pack.bundle_writer().unwrap();
-        writer.write_u16::<LE>(6).unwrap();
-        writer.write_u16::<LE>(u16::swap_bytes(0x00f0)).unwrap();
-        writer.write_u32::<LE>(u32::try_from(total_size).unwrap()).unwrap();
-        writer.write_u32::<LE>(0).unwrap();
+        let writer = pack.bundle_writer()?;
+        let (unknown, reserved) = layout.as_ref()
+            .map(|layout| (layout.unknown, layout.reserved))
+            .unwrap_or((u16::swap_bytes(0x00f0), 0));
+        writer.write_u16::<LE>(format.version())?;
+        writer.write_u16::<LE>(unknown)?;
+        let total_size = u32::try_from(total_size)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "bundle data above 4 GiB"))?;
+        writer.write_u32::<LE>(total_size)?;
+        writer.write_u32::<LE>(reserved)?;
 
         let mut def_buffer = Vec::with_capacity(0x20000);
         for chunk in entry.chunks(0x10000) {
@@ -415,11 +714,11 @@ fn repack_bundle(pack: &mut dyn IBundlePacker, settings: &PackSettings) -> io::R
             e.write_all(chunk).unwrap();
             let buffer = e.finish().unwrap();
             if buffer.len() >= 0x10000 {
-                writer.write_u32::<LE>(0x10000).unwrap();
-                writer.write_all(chunk).unwrap();
+                writer.write_u32::<LE>(0x10000)?;
+                writer.write_all(chunk)?;
             } else {
-                writer.write_u32::<LE>(u32::try_from(buffer.len()).unwrap()).unwrap();
-                writer.write_all(buffer).unwrap();
+                writer.write_u32::<LE>(u32::try_from(buffer.len()).unwrap())?;
+                writer.write_all(buffer)?;
             }
         }
 
@@ -429,8 +728,8 @@ fn repack_bundle(pack: &mut dyn IBundlePacker, settings: &PackSettings) -> io::R
             assert!(chunk.len() <= 0x10000);
             if next == chunk_index  {
                 while next == chunk_index {
-                    writer.write_u32::<LE>(u32::try_from(chunk.len()).unwrap()).unwrap();
-                    writer.write_all(&chunk).unwrap();
+                    writer.write_u32::<LE>(u32::try_from(chunk.len()).unwrap())?;
+                    writer.write_all(&chunk)?;
                     next += 1;
                     if let Some(next_chunk) = list.remove(&next) {
                         chunk = next_chunk;
@@ -442,7 +741,6 @@ fn repack_bundle(pack: &mut dyn IBundlePacker, settings: &PackSettings) -> io::R
             }
         }
         assert_eq!(list.iter().count(), 0);
-    });
-
-    Ok(())
+        Ok(())
+    })
 }
\ No newline at end of file
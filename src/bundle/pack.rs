@@ -1,4 +1,5 @@
 use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::fs;
 use std::fs::File;
@@ -6,22 +7,85 @@ use std::io::Read;
 use std::mem;
 use std::path::Path;
 use std::path::PathBuf;
+use std::time::Instant;
+use std::time::SystemTime;
 use super::*;
+use crate::convert;
+use crate::convert::Convert;
+use crate::index;
+use crate::patch;
+use crate::resource;
+use crate::select::Selector;
 
-const HEADER_FILE: &'static str = "_HEADER";
+const HEADER_FILE: &str = "_HEADER";
+// `<name>.<ext>.deleted` in an unpacked directory packs a deleted entry for
+// that resource, the file holds the flags to use (1 if empty)
+const DELETED_SUFFIX: &str = ".deleted";
+// `unpack --content-hash` lists SHA-256 of resource data (without srpack's
+// index prefix) as `<sha256>  <name>.<ext>` lines
+const CONTENT_HASH_FILE: &str = "_SHA256";
+// `<sha256>  <path>` of converted files written next to the kept resource
+// (textures, media). Files still matching their hash pack the kept resource
+// unchanged instead of being imported, which would inline .stream data.
+const CONVERTED_FILE: &str = "_CONVERTED";
+// format, preamble, order and flags of the unpacked bundle (see manifest.rs),
+// directories without it pack the legacy way from the files' index prefixes
+const MANIFEST_FILE: &str = "_MANIFEST.json";
+
+// How Merge resolves a resource present in more than one bundle.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Conflict {
+    LastWins,
+    FirstWins,
+    Error,
+    // resource with higher index flags wins, ties go to the later bundle
+    PreferNewer,
+}
+
+impl Conflict {
+    pub fn parse(s: &str) -> Option<Self> {
+        Some(match s {
+            "last" | "last-wins" => Conflict::LastWins,
+            "first" | "first-wins" => Conflict::FirstWins,
+            "error" => Conflict::Error,
+            "newer" | "prefer-newer" => Conflict::PreferNewer,
+            _ => return None,
+        })
+    }
+}
+
+// Resource found in more than one bundle (indexes into Merge::bundles).
+pub struct Override {
+    pub file: (u64, u64),
+    pub kept: usize,
+    pub dropped: usize,
+}
 
 pub struct Merge {
     settings: PackSettings,
+    pub conflict: Conflict,
     pub header: Option<[u8; 256]>,
     pub files: HashMap<(u64, u64), Vec<u8>>,
+    // bundles unpacked so far and which one each file came from
+    pub bundles: Vec<PathBuf>,
+    sources: HashMap<(u64, u64), usize>,
+    pub overrides: Vec<Override>,
+    // format and preamble of the newest format merged and the resource order
+    // of all bundles, None if nothing merged had a layout
+    pub layout: Option<Layout>,
 }
 
 impl Merge {
     pub fn new(settings: &PackSettings) -> Self {
         Self {
             settings: settings.clone(),
+            conflict: Conflict::LastWins,
             header: None,
             files: HashMap::new(),
+            bundles: Vec::new(),
+            sources: HashMap::new(),
+            overrides: Vec::new(),
+            layout: None,
         }
     }
 
@@ -31,34 +95,153 @@ impl Merge {
             target,
             header: [0; 256],
             files: HashMap::new(),
+            layout: None,
         };
         work.unpack(&self.settings)?;
+        self.merge(bundle, work.header, work.files, work.layout)
+    }
+
+    pub fn unpack_from<P: AsRef<Path>>(&mut self, bundle: P) -> io::Result<()> {
+        self.unpack_from_(bundle.as_ref())
+    }
+
+    fn unpack_from_dir_(&mut self, dir: &Path) -> io::Result<()> {
+        let unpacked = UnpackedDir::open(dir)?;
+        let header = unpacked.read_header()?;
+        let header = header.get(..256)
+            .and_then(|header| <[u8; 256]>::try_from(header).ok())
+            .unwrap_or([0; 256]);
+        let mut files = HashMap::new();
+        for file in unpacked.files()? {
+            files.insert(file, unpacked.read_file(file)?);
+        }
+        self.merge(dir, header, files, unpacked.layout.clone())
+    }
+
+    // Load unpacked directory (as written by unpack_bundle_to_dir) like a bundle.
+    pub fn unpack_from_dir<P: AsRef<Path>>(&mut self, dir: P) -> io::Result<()> {
+        self.unpack_from_dir_(dir.as_ref())
+    }
+
+    fn merge(
+        &mut self,
+        bundle: &Path,
+        header: [u8; 256],
+        work_files: HashMap<(u64, u64), Vec<u8>>,
+        layout: Option<Layout>,
+    ) -> io::Result<()> {
+        self.merge_layout(layout);
         let files = mem::take(&mut self.files);
 
         let f_cap = files.capacity();
-        let w_cap = work.files.capacity();
+        let w_cap = work_files.capacity();
         let len = if f_cap > w_cap * 2 {
             f_cap
         } else {
             w_cap
         };
 
+        let source = self.bundles.len();
+        self.bundles.push(bundle.to_path_buf());
         self.files = HashMap::with_capacity(len);
-        for (hash, data) in files.into_iter().chain(work.files.into_iter()) {
+        self.files.extend(files);
+        for (hash, data) in work_files.into_iter() {
+            if let Some(old) = self.files.get(&hash) {
+                // None for files inserted into `files` directly
+                let previous = self.sources.get(&hash).copied();
+                let replace = match self.conflict {
+                    Conflict::LastWins => true,
+                    Conflict::FirstWins => false,
+                    Conflict::Error => {
+                        let ext = hash::extension_lookup(hash.1).unwrap_or("?");
+                        let previous = previous
+                            .map(|previous| format!("\"{}\"", self.bundles[previous].display()))
+                            .unwrap_or_else(|| "the merge already".to_string());
+                        return Err(io::Error::new(
+                            io::ErrorKind::AlreadyExists,
+                            format!("{:016x}.{ext} is in {previous} and \"{}\"", hash.0, bundle.display()),
+                        ));
+                    }
+                    Conflict::PreferNewer => resource::prefix_flags(&data) >= resource::prefix_flags(old),
+                };
+
+                if let Some(previous) = previous {
+                    let (kept, dropped) = if replace {
+                        (source, previous)
+                    } else {
+                        (previous, source)
+                    };
+                    self.overrides.push(Override {
+                        file: hash,
+                        kept,
+                        dropped,
+                    });
+                }
+                if !replace {
+                    continue;
+                }
+            }
+            self.sources.insert(hash, source);
             self.files.insert(hash, data);
         }
 
         if self.header.is_none() {
-            self.header = Some(work.header);
-        } else if self.header != Some(work.header) {
+            self.header = Some(header);
+        } else if self.header != Some(header) {
             self.header = Some([0; 256]);
         }
 
         Ok(())
     }
 
-    pub fn unpack_from<P: AsRef<Path>>(&mut self, bundle: P) -> io::Result<()> {
-        self.unpack_from_(bundle.as_ref())
+    // Keep the newest format (VT1 patch bundles are format 5 on top of format 4
+    // bundles) and append resources not seen yet in their bundle's order.
+    fn merge_layout(&mut self, layout: Option<Layout>) {
+        let Some(mut layout) = layout else {
+            return;
+        };
+        let Some(current) = &mut self.layout else {
+            self.layout = Some(layout);
+            return;
+        };
+        if layout.format.version() > current.format.version() {
+            current.format = layout.format;
+            current.unknown = layout.unknown;
+            current.reserved = layout.reserved;
+        }
+        let listed = current.resources.iter()
+            .map(|entry| entry.file)
+            .collect::<std::collections::HashSet<_>>();
+        layout.resources.retain(|entry| !listed.contains(&entry.file));
+        current.resources.append(&mut layout.resources);
+    }
+
+    // Unpack base bundle followed by its patch bundles and drop resources
+    // flagged as deleted.
+    pub fn unpack_chain_from<P: AsRef<Path>>(&mut self, base: P) -> io::Result<()> {
+        for bundle in patch::find_chain(base)? {
+            self.unpack_from(&bundle)?;
+        }
+        self.files.retain(|_, data| {
+            !resource::prefix_flags(data).map(resource::is_deleted).unwrap_or(false)
+        });
+        Ok(())
+    }
+
+    // Write files in the same layout as unpack_bundle_to_dir (without conversions).
+    pub fn unpack_to_dir<P: AsRef<Path>>(&self, dir: P) -> io::Result<()> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        fs::write(dir.join(HEADER_FILE), self.header.unwrap_or([0; 256]))?;
+        for ((name_hash, ext_hash), data) in self.files.iter() {
+            let ext = hash::extension_lookup(*ext_hash)
+                .ok_or_else(|| io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown extension hash {ext_hash:016x}"),
+                ))?;
+            fs::write(dir.join(format!("{name_hash:016x}.{ext}")), data)?;
+        }
+        Ok(())
     }
 
     fn repack_to_(self, bundle: &Path) -> io::Result<()> {
@@ -70,11 +253,24 @@ impl Merge {
         self.repack_to_(bundle.as_ref())
     }
 
+    // Repack into a temporary file next to bundle and replace bundle with it.
+    pub fn repack_in_place<P: AsRef<Path>>(self, bundle: P) -> io::Result<()> {
+        let bundle = bundle.as_ref();
+        let mut temp = bundle.as_os_str().to_owned();
+        temp.push(".tmp");
+        if let Err(e) = self.repack_to_(Path::new(&temp)) {
+            let _ = fs::remove_file(&temp);
+            return Err(e);
+        }
+        fs::rename(&temp, bundle)
+    }
+
     pub fn repack_to_write(self, target: &mut dyn Write) -> io::Result<()> {
         let mut repack = MemoryRepack {
             target,
             header: self.header.unwrap_or([0; 256]),
             files: self.files,
+            layout: self.layout,
         };
         repack.repack(&self.settings)?;
         Ok(())
@@ -84,6 +280,7 @@ struct MemoryUnpack {
     target: File,
     header: [u8; 256],
     files: HashMap<(u64, u64), Vec<u8>>,
+    layout: Option<Layout>,
 }
 
 impl IBundleUnpacker for MemoryUnpack {
@@ -102,6 +299,11 @@ impl IBundleUnpacker for MemoryUnpack {
         self.header.copy_from_slice(data);
         Ok(())
     }
+
+    fn write_layout(&mut self, layout: &Layout) -> io::Result<()> {
+        self.layout = Some(layout.clone());
+        Ok(())
+    }
 }
 
 struct MemoryRepack<'a> {
@@ -109,6 +311,7 @@ struct MemoryRepack<'a> {
     header: [u8; 256],
     // profile sort performance with either (name_hash, ext_hash) or (ext_hash, name_hash)
     files: HashMap<(u64, u64), Vec<u8>>,
+    layout: Option<Layout>,
 }
 
 impl<'a> IBundlePacker for MemoryRepack<'a> {
@@ -130,11 +333,24 @@ impl<'a> IBundlePacker for MemoryRepack<'a> {
     fn read_header(&self) -> io::Result<Cow<[u8]>> {
         Ok(Cow::Borrowed(&self.header[..]))
     }
+
+    fn read_layout(&self) -> io::Result<Option<Layout>> {
+        Ok(self.layout.clone())
+    }
 }
 
 struct Unpack {
     bundle: File,
+    // bundle's .stream file holding streamed texture data
+    stream: Option<File>,
     dir: PathBuf,
+    convert: Convert,
+    string_keys: Option<HashMap<u32, String>>,
+    select: Selector,
+    dictionary: Option<Arc<HashMap<MurmurHash64, String>>>,
+    content_hashes: Option<index::ContentHashes>,
+    // hashes of converted files whose resource is kept, for CONVERTED_FILE
+    exported: Vec<(PathBuf, [u8; 32])>,
 }
 
 impl IBundleUnpacker for Unpack {
@@ -144,6 +360,33 @@ impl IBundleUnpacker for Unpack {
     }
 
     fn write_file(&mut self, file: (u64, u64), data: &[u8]) -> io::Result<()> {
+        if !self.select.matches_data(data, self.dictionary.as_deref()) {
+            return Ok(());
+        }
+        if let Some(hashes) = &mut self.content_hashes {
+            hashes.push((file, index::content_hash(data)));
+        }
+
+        let context = convert::Context {
+            stream: self.stream.as_mut().map(|s| s as &mut dyn convert::ReadSeek),
+            string_keys: self.string_keys.as_ref(),
+        };
+        if let Some(exported) = convert::export(&self.convert, data, context)? {
+            for (path, data) in exported.files {
+                if exported.keep_unpacked {
+                    self.exported.push((path.clone(), hash::sha256(&data)));
+                }
+                let path = self.dir.join(path);
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::write(&path, data)?;
+            }
+            if !exported.keep_unpacked {
+                return Ok(());
+            }
+        }
+
         match hash::extension_lookup(file.1) {
             Some(ext) => self.dir.push(format!("{:016x}.{}", file.0, ext)),
             None => panic!("unknown extension hash {:016x}", file.1),
@@ -160,19 +403,126 @@ impl IBundleUnpacker for Unpack {
         self.dir.pop();
         Ok(())
     }
+
+    fn write_layout(&mut self, layout: &Layout) -> io::Result<()> {
+        fs::write(self.dir.join(MANIFEST_FILE), manifest::to_json(layout)?)
+    }
+}
+
+// Unpacked directory as written by unpack_bundle_to_dir.
+struct UnpackedDir {
+    dir: PathBuf,
+    // files converted by `unpack --convert` (or replaced by hand) to wrap on read
+    converted: HashMap<(u64, u64), PathBuf>,
+    // resources marked deleted with DELETED_SUFFIX files and their flags
+    deleted: HashMap<(u64, u64), u32>,
+    // converted files as exported (from CONVERTED_FILE) by path
+    exported: HashMap<PathBuf, [u8; 32]>,
+    // from MANIFEST_FILE, None for legacy directories
+    layout: Option<Layout>,
+    flags: HashMap<(u64, u64), u32>,
 }
 
 struct Repack {
     bundle: File,
-    dir: PathBuf,
+    unpacked: UnpackedDir,
 }
 
-impl IBundlePacker for Repack {
-    fn bundle_writer(&mut self) -> io::Result<&mut (dyn Write + '_)> {
-        Ok(&mut self.bundle)
+fn read_deleted(path: &Path, relative: &Path) -> io::Result<Option<((u64, u64), u32)>> {
+    let Some(file) = relative.to_str()
+        .and_then(|file| file.strip_suffix(DELETED_SUFFIX))
+        .map(|file| file.replace('\\', "/"))
+        .and_then(|file| resource::parse_file_name(&file))
+    else {
+        return Ok(None);
+    };
+
+    let data = fs::read_to_string(path)?;
+    let flags = match data.trim() {
+        "" => 0x01,
+        flags => flags.parse::<u32>()
+            .ok()
+            .filter(|flags| resource::is_deleted(*flags))
+            .ok_or_else(|| io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("\"{}\" must be empty or hold deleted flags 1 or 2", path.display()),
+            ))?,
+    };
+    Ok(Some((file, flags)))
+}
+
+fn find_converted(
+    root: &Path,
+    dir: &Path,
+    out: &mut HashMap<(u64, u64), PathBuf>,
+    deleted: &mut HashMap<(u64, u64), u32>,
+) -> io::Result<()> {
+    for file in fs::read_dir(dir)? {
+        let file = file?;
+        let metadata = file.metadata()?;
+        let path = file.path();
+        if metadata.is_dir() {
+            find_converted(root, &path, out, deleted)?;
+        } else if metadata.is_file()
+            && let relative = path.strip_prefix(root).unwrap()
+            && let Some((file, flags)) = read_deleted(&path, relative)?
+        {
+            deleted.insert(file, flags);
+        } else if metadata.is_file() && convert::is_importable(&path) {
+            // top level files named like unpacked files are handled as unpacked files
+            let top_level = dir == root;
+            if top_level
+                && let Some(stem) = path.file_stem()
+                && stem.len() == 16
+                && let Some(ext) = path.extension()
+                && let Some(ext) = ext.to_str()
+                && hash::extension_lookup(hash::stingray_hash64(ext.as_bytes())).is_some()
+            {
+                continue;
+            }
+
+            let relative = path.strip_prefix(root).unwrap();
+            if let Some(key) = convert::import_key(relative) {
+                out.insert(key, path);
+            }
+        }
     }
+    Ok(())
+}
 
-    fn files(&self) -> io::Result<Box<dyn Iterator<Item = io::Result<(u64, u64)>> + '_>> {
+impl UnpackedDir {
+    fn open(dir: &Path) -> io::Result<Self> {
+        let mut converted = HashMap::new();
+        let mut deleted = HashMap::new();
+        find_converted(dir, dir, &mut converted, &mut deleted)?;
+        let layout = match fs::read(dir.join(MANIFEST_FILE)) {
+            Ok(data) => Some(manifest::parse(&data)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        let flags = layout.iter()
+            .flat_map(|layout| layout.resources.iter())
+            .map(|entry| (entry.file, entry.flags))
+            .collect();
+        let exported = match fs::read_to_string(dir.join(CONVERTED_FILE)) {
+            Ok(list) => list.lines()
+                .filter_map(|line| line.split_once("  "))
+                .filter_map(|(sha256, path)| Some((dir.join(path), hash::from_hex(sha256)?)))
+                .collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            converted,
+            deleted,
+            exported,
+            layout,
+            flags,
+        })
+    }
+
+    fn files(&self) -> io::Result<Vec<(u64, u64)>> {
         let mut out = Vec::new();
         let files = fs::read_dir(&self.dir)?;
         for file in files {
@@ -185,47 +535,172 @@ impl IBundlePacker for Repack {
                     && stem.len() == 16
                     && let Some(ext) = path.extension()
                     && let Some(ext) = ext.to_str()
+                    && let Ok(name_hash) = u64::from_str_radix(stem, 16)
+                    && let ext_hash = hash::stingray_hash64(ext.as_bytes())
+                    && hash::extension_lookup(ext_hash).is_some()
+                    && !self.converted.contains_key(&(name_hash, ext_hash))
+                    && !self.deleted.contains_key(&(name_hash, ext_hash))
                 {
-                    let name_hash = u64::from_str_radix(&stem, 16).unwrap();
-                    let ext_hash = hash::stingray_hash64(ext.as_bytes());
                     out.push((name_hash, ext_hash));
                 }
             }
         }
-        Ok(Box::new(out.into_iter().map(|hash| Ok(hash))))
+        out.extend(self.converted.keys().filter(|file| !self.deleted.contains_key(file)));
+        out.extend(self.deleted.keys());
+        Ok(out)
     }
 
-    fn read_file(&self, file: (u64, u64)) -> io::Result<Cow<[u8]>> {
-        let file = self.dir.join(&format!(
+    // Manifest flags override the index prefix, deleted markers override both.
+    fn read_file(&self, file: (u64, u64)) -> io::Result<Vec<u8>> {
+        let mut data = self.read_file_(file)?;
+        if !self.deleted.contains_key(&file)
+            && let Some(flags) = self.flags.get(&file)
+            && data.len() >= resource::PREFIX_SIZE
+        {
+            data[16..20].copy_from_slice(&flags.to_le_bytes());
+        }
+        Ok(data)
+    }
+
+    fn read_file_(&self, file: (u64, u64)) -> io::Result<Vec<u8>> {
+        if let Some(flags) = self.deleted.get(&file) {
+            return Ok(resource::tombstone(file.1, file.0, *flags));
+        }
+
+        let path = self.dir.join(format!(
             "{:016x}.{}",
             file.0,
             hash::extension_lookup(file.1).unwrap()
         ));
-        let data = fs::read(&file)?;
-        Ok(Cow::Owned(data))
+
+        if let Some(converted) = self.converted.get(&file) {
+            let data = fs::read(converted)?;
+            let original = match fs::read(&path) {
+                Ok(original) => Some(original),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+                Err(e) => return Err(e),
+            };
+            if let Some(original) = original {
+                if self.exported.get(converted) == Some(&hash::sha256(&data)) {
+                    return Ok(original);
+                }
+                return convert::import(file, converted, &data, Some(&original));
+            }
+            return convert::import(file, converted, &data, None);
+        }
+
+        let data = fs::read(&path)?;
+        if file.1 == convert::lua::EXT_HASH && !resource::is_wrapped(&data, file.1) {
+            return convert::import(file, &path, &data, None);
+        }
+        Ok(data)
     }
 
-    fn read_header(&self) -> io::Result<Cow<[u8]>> {
+    fn read_header(&self) -> io::Result<Vec<u8>> {
         let file = self.dir.join(HEADER_FILE);
-        let data = fs::read(&file)?;
-        Ok(Cow::Owned(data))
+        fs::read(&file)
     }
 }
 
-pub fn unpack_bundle_to_dir<B: AsRef<Path>, D: AsRef<Path>>(
-    bundle: B,
-    dir: D,
+impl IBundlePacker for Repack {
+    fn bundle_writer(&mut self) -> io::Result<&mut (dyn Write + '_)> {
+        Ok(&mut self.bundle)
+    }
+
+    fn files(&self) -> io::Result<Box<dyn Iterator<Item = io::Result<(u64, u64)>> + '_>> {
+        let files = self.unpacked.files()?;
+        Ok(Box::new(files.into_iter().map(Ok)))
+    }
+
+    fn read_file(&self, file: (u64, u64)) -> io::Result<Cow<'_, [u8]>> {
+        self.unpacked.read_file(file).map(Cow::Owned)
+    }
+
+    fn read_header(&self) -> io::Result<Cow<'_, [u8]>> {
+        self.unpacked.read_header().map(Cow::Owned)
+    }
+
+    fn read_layout(&self) -> io::Result<Option<Layout>> {
+        Ok(self.unpacked.layout.clone())
+    }
+}
+
+fn write_exported(dir: &Path, mut exported: Vec<(PathBuf, [u8; 32])>) -> io::Result<()> {
+    if exported.is_empty() {
+        return Ok(());
+    }
+    exported.sort_unstable();
+    let mut list = String::new();
+    for (path, sha256) in exported {
+        let path = path.to_string_lossy().replace('\\', "/");
+        list.push_str(&format!("{}  {path}\n", hash::to_hex(&sha256)));
+    }
+    fs::write(dir.join(CONVERTED_FILE), list)
+}
+
+fn unpack_bundle_to_dir_(
+    bundle: &Path,
+    dir: &Path,
     settings: &PackSettings,
-) -> io::Result<()> {
-    let bundle = bundle.as_ref();
-    let dir = dir.as_ref();
+    salvage: bool,
+) -> io::Result<Option<Salvage>> {
     assert!(bundle.exists());
+    let mut stream = bundle.as_os_str().to_owned();
+    stream.push(".stream");
+    let stream = File::open(stream).ok();
     let bundle = File::open(bundle)?;
     let mut unpack = Unpack {
         bundle,
+        stream,
         dir: dir.to_path_buf(),
+        convert: settings.convert,
+        string_keys: settings.dictionary.as_ref()
+            .filter(|_| settings.convert.strings)
+            .map(|dict| {
+                dict.values()
+                    .map(|key| (convert::strings::key_hash(key), key.clone()))
+                    .collect()
+            }),
+        select: settings.select.clone(),
+        dictionary: settings.dictionary.clone(),
+        content_hashes: settings.content_hash.then(Vec::new),
+        exported: Vec::new(),
+    };
+    let salvaged = if salvage {
+        Some(salvage_bundle(&mut unpack)?)
+    } else {
+        unpack.unpack(settings)?;
+        None
     };
-    unpack.unpack(settings)
+
+    if let Some(mut hashes) = unpack.content_hashes {
+        hashes.sort_unstable_by_key(|((name_hash, ext_hash), _)| (*ext_hash, *name_hash));
+        let mut list = String::new();
+        for ((name_hash, ext_hash), sha256) in hashes {
+            let ext = hash::extension_lookup(ext_hash).unwrap();
+            list.push_str(&format!("{}  {name_hash:016x}.{ext}\n", hash::to_hex(&sha256)));
+        }
+        fs::write(dir.join(CONTENT_HASH_FILE), list)?;
+    }
+    write_exported(dir, unpack.exported)?;
+    Ok(salvaged)
+}
+
+pub fn unpack_bundle_to_dir<B: AsRef<Path>, D: AsRef<Path>>(
+    bundle: B,
+    dir: D,
+    settings: &PackSettings,
+) -> io::Result<()> {
+    unpack_bundle_to_dir_(bundle.as_ref(), dir.as_ref(), settings, false).map(|_| ())
+}
+
+// Unpack what can be recovered from a damaged or truncated bundle.
+pub fn salvage_bundle_to_dir<B: AsRef<Path>, D: AsRef<Path>>(
+    bundle: B,
+    dir: D,
+    settings: &PackSettings,
+) -> io::Result<Salvage> {
+    unpack_bundle_to_dir_(bundle.as_ref(), dir.as_ref(), settings, true).map(|salvage| salvage.unwrap())
 }
 
 pub fn pack_dir_to_bundle<D: AsRef<Path>, B: AsRef<Path>>(
@@ -235,24 +710,476 @@ pub fn pack_dir_to_bundle<D: AsRef<Path>, B: AsRef<Path>>(
 ) -> io::Result<()> {
     let dir = dir.as_ref();
     let bundle = bundle.as_ref();
-    assert!(dir.exists());
-    let bundle = File::create(bundle)?;
+    let unpacked = UnpackedDir::open(dir)?;
+    // write next to bundle and rename so the bundle is never half written
+    let mut temp = bundle.as_os_str().to_owned();
+    temp.push(".tmp");
     let mut pack = Repack {
-        dir: dir.to_path_buf(),
-        bundle,
+        bundle: File::create(&temp)?,
+        unpacked,
     };
-    pack.repack(settings)
+    if let Err(e) = pack.repack(settings) {
+        drop(pack);
+        let _ = fs::remove_file(&temp);
+        return Err(e);
+    }
+    drop(pack);
+    fs::rename(&temp, bundle)
 }
 
+// (size, modification time) of every file below dir.
+fn snapshot(dir: &Path, skip: &[&Path], out: &mut BTreeMap<PathBuf, (u64, SystemTime)>) -> io::Result<()> {
+    for file in fs::read_dir(dir)? {
+        let path = file?.path();
+        if skip.contains(&path.as_path()) {
+            continue;
+        }
+        // files may disappear while editors save them
+        let Ok(metadata) = fs::metadata(&path) else {
+            continue;
+        };
+        if metadata.is_dir() {
+            snapshot(&path, skip, out)?;
+        } else {
+            out.insert(path, (metadata.len(), metadata.modified()?));
+        }
+    }
+    Ok(())
+}
+
+// Pack dir into bundle, then poll dir and repack once changes have settled
+// for `debounce`. `packed` gets the result of every pack and returns false
+// to stop watching.
+pub fn watch_dir_to_bundle<D: AsRef<Path>, B: AsRef<Path>>(
+    dir: D,
+    bundle: B,
+    settings: &PackSettings,
+    debounce: Duration,
+    mut packed: impl FnMut(io::Result<()>) -> bool,
+) -> io::Result<()> {
+    let dir = dir.as_ref();
+    let bundle = bundle.as_ref();
+    // bundle may be written into the watched directory, compare canonical
+    // paths to skip it and its temporary file
+    let parent = match bundle.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let bundle_path = fs::canonicalize(parent)?.join(bundle.file_name().unwrap_or_default());
+    let mut temp_path = bundle_path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let skip = [bundle_path.as_path(), Path::new(&temp_path)];
+    let dir_path = fs::canonicalize(dir)?;
+
+    let read = |out: &mut BTreeMap<_, _>| {
+        out.clear();
+        snapshot(&dir_path, &skip, out)
+    };
+    let mut last = BTreeMap::new();
+    read(&mut last)?;
+    if !packed(pack_dir_to_bundle(dir, bundle, settings)) {
+        return Ok(());
+    }
+
+    let poll = (debounce / 4).max(Duration::from_millis(50));
+    let mut current = BTreeMap::new();
+    let mut changed = None;
+    loop {
+        thread::sleep(poll);
+        read(&mut current)?;
+        if current != last {
+            mem::swap(&mut current, &mut last);
+            changed = Some(Instant::now());
+        } else if changed.is_some_and(|time| time.elapsed() >= debounce) {
+            changed = None;
+            if !packed(pack_dir_to_bundle(dir, bundle, settings)) {
+                return Ok(());
+            }
+        }
+    }
+}
+
+
+
+
+
+
+
+
 
 
 
 
 
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resource::Resource;
+    use crate::resource::Variant;
 
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("srpack-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // Unpack resources with conversions into dir, as unpack_bundle_to_dir does.
+    fn unpack_converted(dir: &Path, convert: &str, stream: &[u8], files: &[&[u8]]) {
+        let stream_path = dir.join("bundle.stream");
+        fs::write(&stream_path, stream).unwrap();
+        let mut unpack = Unpack {
+            bundle: File::open(&stream_path).unwrap(),
+            stream: Some(File::open(&stream_path).unwrap()),
+            dir: dir.to_path_buf(),
+            convert: Convert::parse(convert).unwrap(),
+            string_keys: None,
+            select: Selector::default(),
+            dictionary: None,
+            content_hashes: None,
+            exported: Vec::new(),
+        };
+        unpack.write_header(&[0; 256]).unwrap();
+        for data in files {
+            let resource = Resource::parse(data).unwrap();
+            unpack.write_file((resource.name_hash, resource.ext_hash), data).unwrap();
+        }
+        fs::remove_file(&stream_path).unwrap();
+        write_exported(dir, unpack.exported).unwrap();
+    }
+
+    #[test]
+    fn unedited_texture_repacks_unchanged() {
+        // Stingray header, DDS header and the smallest mip, larger mips streamed
+        let mut data = vec![7; 16];
+        data.extend(b"DDS ");
+        data.extend([0; 124]);
+        data.extend([1; 32]);
+        let texture = Resource {
+            ext_hash: convert::texture::EXT_HASH,
+            name_hash: 0x1234,
+            flags: 0,
+            stream_offset: 0,
+            variants: vec![Variant { language: 0, stream_size: 64, data: &data }],
+        }.to_vec();
+
+        let dir = temp_dir("texture-roundtrip");
+        unpack_converted(&dir, "textures", &[2; 64], &[&texture]);
+        let unpacked = UnpackedDir::open(&dir).unwrap();
+        let file = (0x1234, convert::texture::EXT_HASH);
+        assert_eq!(unpacked.files().unwrap(), vec![file]);
+        assert_eq!(unpacked.read_file(file).unwrap(), texture);
+
+        // edited DDS is imported with the streamed data inlined
+        let dds = dir.join("0000000000001234.dds");
+        let mut edited = fs::read(&dds).unwrap();
+        *edited.last_mut().unwrap() = 9;
+        fs::write(&dds, &edited).unwrap();
+        let unpacked = UnpackedDir::open(&dir).unwrap();
+        let data = unpacked.read_file(file).unwrap();
+        let resource = Resource::parse(&data).unwrap();
+        assert_eq!(resource.variants[0].stream_size, 0);
+        assert!(resource.variants[0].data.ends_with(&edited[128..]));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unedited_media_repacks_unchanged() {
+        // Stingray header with the size of the bank, bank data in the .stream file
+        let mut data = vec![0; 12];
+        data.extend(16u32.to_le_bytes());
+        let mut bank = b"BKHD".to_vec();
+        bank.extend(8u32.to_le_bytes());
+        bank.extend([3; 8]);
+        let media = Resource {
+            ext_hash: convert::media::WWISE_BANK,
+            name_hash: 0x5678,
+            flags: 0,
+            stream_offset: 0,
+            variants: vec![Variant { language: 0, stream_size: 16, data: &data }],
+        }.to_vec();
+
+        let dir = temp_dir("media-roundtrip");
+        unpack_converted(&dir, "media", &bank, &[&media]);
+        let unpacked = UnpackedDir::open(&dir).unwrap();
+        let file = (0x5678, convert::media::WWISE_BANK);
+        assert_eq!(unpacked.files().unwrap(), vec![file]);
+        assert_eq!(unpacked.read_file(file).unwrap(), media);
+
+        let path = dir.join("0000000000005678.wwise_bank.bnk");
+        bank.extend([4; 4]);
+        fs::write(&path, &bank).unwrap();
+        let unpacked = UnpackedDir::open(&dir).unwrap();
+        let data = unpacked.read_file(file).unwrap();
+        let resource = Resource::parse(&data).unwrap();
+        assert_eq!(resource.variants[0].stream_size, 0);
+        assert_eq!(resource.variants[0].data[12..16], 20u32.to_le_bytes());
+        assert_eq!(&resource.variants[0].data[16..], &bank[..]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn in_place_edit_keeps_format_and_order() {
+        let package = hash::stingray_hash64(b"package");
+        let files = [3u64, 1, 2].map(|name| ((name, package), resource::wrap(package, name, b"data")));
+        let mut bundle = Vec::new();
+        MemoryRepack {
+            target: &mut bundle,
+            header: [0; 256],
+            files: files.iter().cloned().collect(),
+            layout: Some(Layout {
+                format: BundleFormat::Five,
+                unknown: 0x1234,
+                reserved: 0,
+                resources: files.iter()
+                    .map(|(file, _)| LayoutEntry { file: *file, flags: 0, size: 4, languages: vec![0] })
+                    .collect(),
+            }),
+        }.repack(&settings()).unwrap();
+
+        let dir = temp_dir("in-place");
+        let path = dir.join("0123456789abcdef");
+        fs::write(&path, &bundle).unwrap();
+        let mut merge = Merge::new(&settings());
+        merge.unpack_from(&path).unwrap();
+        merge.files.insert((4, package), resource::wrap(package, 4, b"new"));
+        merge.repack_in_place(&path).unwrap();
+
+        let data = fs::read(&path).unwrap();
+        assert_eq!(data[..4], [5, 0, 0x34, 0x12]);
+        let names = index::extract_index(&path).unwrap()
+            .into_iter()
+            .map(|(_, name_hash, flags, size)| (name_hash, flags.is_some(), size.is_some()))
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec![(3, true, false), (1, true, false), (2, true, false), (4, true, false)]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn merge_over_inserted_file() {
+        let package = hash::stingray_hash64(b"package");
+        let dir = temp_dir("merge-inserted");
+        let bundle = dir.join("0123456789abcdef");
+        let mut data = Vec::new();
+        MemoryRepack {
+            target: &mut data,
+            header: [0; 256],
+            files: [((1, package), resource::wrap(package, 1, b"bundle"))].into_iter().collect(),
+            layout: None,
+        }.repack(&settings()).unwrap();
+        fs::write(&bundle, &data).unwrap();
+
+        for conflict in [Conflict::LastWins, Conflict::Error] {
+            let mut merge = Merge::new(&settings());
+            merge.conflict = conflict;
+            merge.files.insert((1, package), resource::wrap(package, 1, b"inserted"));
+            let result = merge.unpack_from(&bundle);
+            assert_eq!(result.is_ok(), conflict == Conflict::LastWins);
+            assert!(merge.overrides.is_empty());
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
 
+    #[test]
+    fn watch_continues_after_failed_pack() {
+        let dir = temp_dir("watch");
+        let src = dir.join("src");
+        fs::create_dir_all(&src).unwrap();
+        let file = src.join("0000000000000001.package");
+        // too small for the index prefix
+        fs::write(&file, [1, 2, 3]).unwrap();
 
+        let bundle = dir.join("0123456789abcdef");
+        let settings = settings();
+        let package = hash::stingray_hash64(b"package");
+        let mut results = Vec::new();
+        watch_dir_to_bundle(&src, &bundle, &settings, Duration::from_millis(20), |result| {
+            results.push(result.is_ok());
+            if results.len() == 1 {
+                fs::write(&file, resource::wrap(package, 1, b"fixed")).unwrap();
+            }
+            results.len() < 2
+        }).unwrap();
 
+        assert_eq!(results, vec![false, true]);
+        let index = index::extract_index(&bundle).unwrap();
+        assert_eq!(index.len(), 1);
+        assert_eq!((index[0].0, index[0].1), (package, 1));
+        fs::remove_dir_all(&dir).unwrap();
+    }
 
+    // Bundle of 8 resources of 30000 bytes each, spanning 4 chunks, and the
+    // (name_hash, ext_hash) of its resources in order.
+    fn salvage_bundle(dir: &Path) -> (PathBuf, Vec<(u64, u64)>) {
+        let lua = hash::stingray_hash64(b"lua");
+        let mut seed = 1u32;
+        let files = (1..=8u64)
+            .map(|name| {
+                let payload = (0..30000)
+                    .map(|_| {
+                        seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+                        b'a' + (seed >> 30) as u8
+                    })
+                    .collect::<Vec<_>>();
+                ((name, lua), resource::wrap(lua, name, &payload))
+            })
+            .collect();
+        let mut bundle = Vec::new();
+        MemoryRepack {
+            target: &mut bundle,
+            header: [0; 256],
+            files,
+            layout: None,
+        }.repack(&settings()).unwrap();
+
+        let path = dir.join("0123456789abcdef");
+        fs::write(&path, &bundle).unwrap();
+        let keys = index::extract_index(&path).unwrap()
+            .into_iter()
+            .map(|(ext_hash, name_hash, ..)| (name_hash, ext_hash))
+            .collect();
+        (path, keys)
+    }
 
+    // Offsets of the chunks' size fields.
+    fn chunk_offsets(data: &[u8]) -> Vec<usize> {
+        let mut offsets = Vec::new();
+        let mut offset = 12;
+        while offset < data.len() {
+            offsets.push(offset);
+            offset += 4 + u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap()) as usize;
+        }
+        offsets
+    }
+
+    // Bundle of small and large resources over several chunks, the large
+    // random one stored uncompressed.
+    fn mixed_bundle(dir: &Path) -> PathBuf {
+        let lua = hash::stingray_hash64(b"lua");
+        let mut seed = 1u32;
+        let mut random = |len: usize, bits: u32| {
+            (0..len)
+                .map(|_| {
+                    seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+                    (seed >> (32 - bits)) as u8
+                })
+                .collect::<Vec<_>>()
+        };
+        let payloads = [random(10, 2), random(200000, 8), random(100, 2), random(30000, 2), random(1, 8), random(70000, 3)];
+        let files = payloads.iter()
+            .enumerate()
+            .map(|(name, payload)| ((name as u64, lua), resource::wrap(lua, name as u64, payload)))
+            .collect();
+        let mut bundle = Vec::new();
+        MemoryRepack {
+            target: &mut bundle,
+            header: [0; 256],
+            files,
+            layout: None,
+        }.repack(&settings()).unwrap();
+
+        assert!(chunk_offsets(&bundle).iter().any(|&offset| bundle[offset..offset + 4] == 0x10000u32.to_le_bytes()));
+        let path = dir.join("0123456789abcdef");
+        fs::write(&path, &bundle).unwrap();
+        path
+    }
+
+    fn settings() -> PackSettings {
+        PackSettings {
+            num_threads: 1,
+            convert: Convert::default(),
+            dictionary: None,
+            select: Selector::default(),
+            content_hash: false,
+        }
+    }
+
+    #[test]
+    fn salvage_truncated_tail() {
+        let dir = temp_dir("salvage-tail");
+        let (bundle, keys) = salvage_bundle(&dir);
+        let mut data = fs::read(&bundle).unwrap();
+        let offsets = chunk_offsets(&data);
+        assert_eq!(offsets.len(), 4);
+        // half of the third chunk is left
+        data.truncate((offsets[2] + offsets[3]) / 2);
+        fs::write(&bundle, &data).unwrap();
+
+        let salvage = salvage_bundle_to_dir(&bundle, dir.join("out"), &settings()).unwrap();
+        assert_eq!((salvage.bad_chunks, salvage.chunks), (2, 4));
+        // resources up to the end of the second chunk at 0x20000
+        assert_eq!(salvage.recovered, 4);
+        assert_eq!(salvage.lost, keys[4..]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn salvage_corrupt_middle_chunk() {
+        let dir = temp_dir("salvage-middle");
+        let (bundle, keys) = salvage_bundle(&dir);
+        let mut data = fs::read(&bundle).unwrap();
+        let offsets = chunk_offsets(&data);
+        // break the zlib header of the second chunk
+        data[offsets[1] + 4..offsets[1] + 6].fill(0);
+        fs::write(&bundle, &data).unwrap();
+
+        let salvage = salvage_bundle_to_dir(&bundle, dir.join("out"), &settings()).unwrap();
+        assert_eq!((salvage.bad_chunks, salvage.chunks), (1, 4));
+        // resources 2 to 4 touch 0x10000..0x20000, the rest are found again
+        assert_eq!(salvage.recovered, 5);
+        assert_eq!(salvage.lost, keys[2..5]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn salvage_missing_index_chunk() {
+        let dir = temp_dir("salvage-index");
+        let (bundle, _) = salvage_bundle(&dir);
+        let mut data = fs::read(&bundle).unwrap();
+        data[16..18].fill(0);
+        fs::write(&bundle, &data).unwrap();
+
+        assert!(salvage_bundle_to_dir(&bundle, dir.join("out"), &settings()).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn extract_matches_unpack() {
+        let dir = temp_dir("extract");
+        let bundle = mixed_bundle(&dir);
+        let mut merge = Merge::new(&settings());
+        merge.unpack_from(&bundle).unwrap();
+        assert_eq!(merge.files.len(), 6);
+        for (file, data) in merge.files.iter() {
+            assert_eq!(index::extract_resource(&bundle, *file).unwrap().as_ref(), Some(data));
+        }
+        assert_eq!(index::extract_resource(&bundle, (99, 0)).unwrap(), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stats_shares_add_up() {
+        let dir = temp_dir("stats");
+        let bundle = mixed_bundle(&dir);
+        let stats = index::bundle_stats(&bundle).unwrap();
+        let shares = stats.resources.iter().map(|r| r.compressed).sum::<usize>();
+        // the rest is the share of the header, index and padding
+        assert!(shares <= stats.compressed && shares > stats.compressed * 19 / 20);
+
+        // a few hundred compressed bytes still give every resource a share
+        let package = hash::stingray_hash64(b"package");
+        let mut data = Vec::new();
+        MemoryRepack {
+            target: &mut data,
+            header: [0; 256],
+            files: (1..=3).map(|name| ((name, package), resource::wrap(package, name, &[name as u8; 100]))).collect(),
+            layout: None,
+        }.repack(&settings()).unwrap();
+        fs::write(&bundle, &data).unwrap();
+        let stats = index::bundle_stats(&bundle).unwrap();
+        assert!(stats.compressed < 0x1000);
+        assert!(stats.resources.iter().all(|r| r.compressed > 0));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,220 @@
+/// On-disk cache of `index::extract_index` results for `scan --cache`.
+///
+/// Layout (all little endian):
+///
+///     version: u32
+///     num_bundles: u32
+///     num_bundles * {
+///         path_len: u32
+///         path: path_len bytes    canonical path of bundle (UTF-8)
+///         size: u64               size of bundle file
+///         mtime: u64              modification time, nanoseconds since epoch
+///         format: u16             bundle format (4, 5 or 6)
+///         num_resources: u32
+///         num_resources * (ext_hash: u64, name_hash: u64, flags: u32 (5, 6), size: u32 (6))
+///     }
+///
+/// Bundles are re-indexed when their size or modification time changed.
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+use byteorder::ReadBytesExt;
+use byteorder::WriteBytesExt;
+use byteorder::LE;
+
+use crate::bundle::BundleFormat;
+use crate::index;
+use crate::index::Index;
+
+const VERSION: u32 = 1;
+
+struct Entry {
+    size: u64,
+    mtime: u64,
+    format: BundleFormat,
+    index: Index,
+    // looked up or refreshed in this run
+    used: bool,
+}
+
+pub struct IndexCache {
+    entries: Mutex<HashMap<String, Entry>>,
+    changed: Mutex<bool>,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn key(path: &Path) -> io::Result<(String, u64, u64)> {
+    let metadata = fs::metadata(path)?;
+    let mtime = metadata.modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_nanos() as u64)
+        .unwrap_or(0);
+    let path = fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path));
+    Ok((path.to_string_lossy().into_owned(), metadata.len(), mtime))
+}
+
+impl IndexCache {
+    fn parse(data: &[u8]) -> io::Result<HashMap<String, Entry>> {
+        let mut rdr = data;
+        if rdr.read_u32::<LE>()? != VERSION {
+            return Err(invalid("unsupported index cache version"));
+        }
+        let num_bundles = rdr.read_u32::<LE>()? as usize;
+        let mut entries = HashMap::with_capacity(num_bundles.min(data.len() / 30));
+        for _ in 0..num_bundles {
+            let len = rdr.read_u32::<LE>()? as usize;
+            if rdr.len() < len {
+                return Err(invalid("path runs past end of index cache"));
+            }
+            let path;
+            (path, rdr) = rdr.split_at(len);
+            let path = String::from_utf8(path.to_vec()).map_err(|_| invalid("path is not UTF-8"))?;
+            let size = rdr.read_u64::<LE>()?;
+            let mtime = rdr.read_u64::<LE>()?;
+            let format = match rdr.read_u16::<LE>()? {
+                6 => BundleFormat::Six,
+                5 => BundleFormat::Five,
+                4 => BundleFormat::Four,
+                _ => return Err(invalid("unsupported bundle format in index cache")),
+            };
+            let num_resources = rdr.read_u32::<LE>()? as usize;
+            let mut index = Vec::with_capacity(num_resources.min(rdr.len() / 16));
+            for _ in 0..num_resources {
+                let ext_hash = rdr.read_u64::<LE>()?;
+                let name_hash = rdr.read_u64::<LE>()?;
+                let flags = (format != BundleFormat::Four).then(|| rdr.read_u32::<LE>()).transpose()?;
+                let size = (format == BundleFormat::Six).then(|| rdr.read_u32::<LE>()).transpose()?;
+                index.push((ext_hash, name_hash, flags, size));
+            }
+            entries.insert(path, Entry {
+                size,
+                mtime,
+                format,
+                index,
+                used: false,
+            });
+        }
+        Ok(entries)
+    }
+
+    // Read cache, starting empty if it is missing or unreadable.
+    pub fn read<P: AsRef<Path>>(path: P) -> Self {
+        let entries = fs::read(path)
+            .and_then(|data| Self::parse(&data))
+            .unwrap_or_default();
+        Self {
+            entries: Mutex::new(entries),
+            changed: Mutex::new(false),
+        }
+    }
+
+    fn to_vec(entries: &HashMap<String, Entry>) -> Vec<u8> {
+        let mut paths = entries.keys().collect::<Vec<_>>();
+        paths.sort_unstable();
+
+        let mut out = Vec::new();
+        out.write_u32::<LE>(VERSION).unwrap();
+        out.write_u32::<LE>(u32::try_from(paths.len()).unwrap()).unwrap();
+        for path in paths {
+            let entry = &entries[path];
+            let format = entry.format.version();
+            out.write_u32::<LE>(u32::try_from(path.len()).unwrap()).unwrap();
+            out.extend(path.as_bytes());
+            out.write_u64::<LE>(entry.size).unwrap();
+            out.write_u64::<LE>(entry.mtime).unwrap();
+            out.write_u16::<LE>(format).unwrap();
+            out.write_u32::<LE>(u32::try_from(entry.index.len()).unwrap()).unwrap();
+            for (ext_hash, name_hash, flags, size) in entry.index.iter() {
+                out.write_u64::<LE>(*ext_hash).unwrap();
+                out.write_u64::<LE>(*name_hash).unwrap();
+                if format >= 5 {
+                    out.write_u32::<LE>(flags.unwrap_or(0)).unwrap();
+                }
+                if format >= 6 {
+                    out.write_u32::<LE>(size.unwrap_or(0)).unwrap();
+                }
+            }
+        }
+        out
+    }
+
+    // Format and index of bundle, from the cache if the bundle is unchanged.
+    pub fn index<P: AsRef<Path>>(&self, bundle: P) -> io::Result<(BundleFormat, Index)> {
+        let bundle = bundle.as_ref();
+        let (path, size, mtime) = key(bundle)?;
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(&path)
+            && entry.size == size
+            && entry.mtime == mtime
+        {
+            entry.used = true;
+            return Ok((entry.format, entry.index.clone()));
+        }
+
+        let (format, index) = index::extract_index_with_format(bundle)?;
+        self.entries.lock().unwrap().insert(path, Entry {
+            size,
+            mtime,
+            format,
+            index: index.clone(),
+            used: true,
+        });
+        *self.changed.lock().unwrap() = true;
+        Ok((format, index))
+    }
+
+    // Write cache through a temporary file if anything changed. Entries of
+    // bundles that no longer exist are dropped.
+    pub fn write<P: AsRef<Path>>(self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        let mut entries = self.entries.into_inner().unwrap();
+        let len = entries.len();
+        entries.retain(|bundle, entry| entry.used || Path::new(bundle).exists());
+        if !self.changed.into_inner().unwrap() && entries.len() == len && path.exists() {
+            return Ok(());
+        }
+
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".tmp");
+        fs::write(&temp, Self::to_vec(&entries))?;
+        fs::rename(&temp, path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let mut entries = HashMap::new();
+        for (path, format, index) in [
+            ("a", BundleFormat::Six, vec![(1, 2, Some(0), Some(10)), (3, 4, Some(1), Some(0))]),
+            ("b", BundleFormat::Five, vec![(5, 6, Some(0), None)]),
+            ("c", BundleFormat::Four, vec![(7, 8, None, None)]),
+            ("d", BundleFormat::Five, vec![]),
+        ] {
+            entries.insert(path.to_string(), Entry {
+                size: 100,
+                mtime: 200,
+                format,
+                index,
+                used: false,
+            });
+        }
+
+        let parsed = IndexCache::parse(&IndexCache::to_vec(&entries)).unwrap();
+        assert_eq!(parsed.len(), 4);
+        for (path, entry) in entries.iter() {
+            assert!(parsed[path].format == entry.format);
+            assert_eq!(parsed[path].index, entry.index);
+            assert_eq!((parsed[path].size, parsed[path].mtime), (100, 200));
+        }
+    }
+}
@@ -370,6 +370,8 @@ pub struct CommandBuilder {
     params: Option<&'static [&'static str]>,
     desc: Option<&'static str>,
     short_desc: Option<&'static str>,
+    // last parameter accepts any number of arguments
+    variadic: bool,
 }
 
 impl CommandBuilder {
@@ -392,6 +394,7 @@ impl CommandBuilder {
             params: None,
             desc: None,
             short_desc: None,
+            variadic: false,
         }
     }
 
@@ -415,6 +418,11 @@ impl CommandBuilder {
         self
     }
 
+    pub const fn with_variadic(mut self) -> Self {
+        self.variadic = true;
+        self
+    }
+
     pub const fn name(&self) -> Option<&'static str> {
         self.name
     }
@@ -463,6 +471,9 @@ impl CommandBuilder {
                 for param in params.iter() {
                     write!(pipe, " [{param}]")?;
                 }
+                if self.variadic {
+                    write!(pipe, "...")?;
+                }
             }
             writeln!(pipe)?;
         }
@@ -481,7 +492,8 @@ impl CommandBuilder {
     }
 }
 
-pub struct Params<'c>(&'c [Option<OsString>]);
+// Parameters followed by extra arguments of variadic commands.
+pub struct Params<'c>(&'c [Option<OsString>], &'c [OsString]);
 
 impl<'c> Iterator for Params<'c> {
     type Item = &'c OsStr;
@@ -491,6 +503,9 @@ impl<'c> Iterator for Params<'c> {
             let out;
             (out, self.0) = self.0.split_at(1);
             out.get(0).and_then(|o| o.as_ref().map(|o| o.as_os_str()))
+        } else if let Some((out, rest)) = self.1.split_first() {
+            self.1 = rest;
+            Some(out.as_os_str())
         } else {
             None
         }
@@ -508,6 +523,7 @@ pub struct Command<
     switches: &'static [SwitchInner],
     switches_active: [bool; MAX_UNIQUE_SWITCHES],
     switches_params: [Option<OsString>; MAX_SWITCH_PARAMETERS],
+    variadic: Vec<OsString>,
     unused: usize,
 }
 
@@ -533,6 +549,7 @@ impl<
             switches,
             switches_active: array::from_fn(|_| false),
             switches_params: array::from_fn(|_| None),
+            variadic: Vec::new(),
             unused: 0,
         }
     }
@@ -595,7 +612,7 @@ impl<
     }
 
     pub fn params(&self) -> Params {
-        Params(&self.params[..])
+        Params(&self.params[..], &self.variadic[..])
     }
 
     fn switch_active_(&self, switch: &str) -> bool {
@@ -623,7 +640,7 @@ impl<
             && let Some(&(_, active_index, offset, num_params)) = self.switches.get(i)
             && let Some(true) = self.switches_active.get(active_index)
         {
-            Some(Params(&self.switches_params[offset..offset + num_params]))
+            Some(Params(&self.switches_params[offset..offset + num_params], &[]))
         } else {
             None
         }
@@ -900,6 +917,8 @@ impl Cli {
                 cmd.insert_switch_param(i, arg);
             } else if cmd.params_left() > 0 {
                 cmd.insert_param(arg);
+            } else if cmd.command.variadic {
+                cmd.variadic.push(arg);
             } else {
                 cmd.unused += 1;
                 writeln!(pipe, "unused argument \"{}\"", Path::new(&arg).display())?;
@@ -0,0 +1,195 @@
+use std::io;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+use byteorder::ReadBytesExt;
+use byteorder::WriteBytesExt;
+use byteorder::LE;
+
+use crate::hash;
+use crate::resource;
+use crate::resource::Resource;
+
+pub const EXT_HASH: u64 = 0xa14e8dfa2cd117e2;
+
+// Stingray header in front of lua payload: size (u32) followed by two u32 of
+// unknown use that are kept from the original resource, or written as zero.
+const HEADER_SIZE: usize = 12;
+const LUAJIT_MAGIC: &[u8] = b"\x1bLJ";
+// LuaJIT dump flag for bytecode without debug info (and without chunk name)
+const LUAJIT_FLAG_STRIP: u64 = 0x02;
+
+pub enum Body<'a> {
+    Source(&'a [u8]),
+    Bytecode(&'a [u8]),
+}
+
+fn has_header(data: &[u8]) -> bool {
+    !data.starts_with(LUAJIT_MAGIC)
+        && data.len() >= HEADER_SIZE
+        && (&data[..4]).read_u32::<LE>().unwrap() as usize == data.len() - HEADER_SIZE
+}
+
+pub fn body(data: &[u8]) -> Body<'_> {
+    if data.starts_with(LUAJIT_MAGIC) {
+        Body::Bytecode(data)
+    } else if has_header(data) {
+        let data = &data[HEADER_SIZE..];
+        if data.starts_with(LUAJIT_MAGIC) {
+            Body::Bytecode(data)
+        } else {
+            Body::Source(data)
+        }
+    } else {
+        Body::Source(data)
+    }
+}
+
+fn read_uleb128(data: &mut &[u8]) -> Option<u64> {
+    let mut out = 0;
+    let mut shift = 0;
+    loop {
+        let (&byte, rest) = data.split_first()?;
+        *data = rest;
+        out |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Some(out);
+        }
+        shift += 7;
+        if shift >= 64 {
+            return None;
+        }
+    }
+}
+
+// Chunk name embedded in LuaJIT bytecode dump (for example "@scripts/main.lua").
+pub fn chunk_name(bytecode: &[u8]) -> Option<&str> {
+    let mut data = bytecode.strip_prefix(LUAJIT_MAGIC)?;
+    let (_version, rest) = data.split_first()?;
+    data = rest;
+    let flags = read_uleb128(&mut data)?;
+    if flags & LUAJIT_FLAG_STRIP != 0 {
+        return None;
+    }
+    let len = usize::try_from(read_uleb128(&mut data)?).ok()?;
+    std::str::from_utf8(data.get(..len)?).ok()
+}
+
+// Turn chunk name into relative path, only if it hashes to the resource name.
+fn chunk_path(chunk: &str, name_hash: u64) -> Option<PathBuf> {
+    let name = chunk.strip_prefix('@').unwrap_or(chunk);
+    let name = name.strip_suffix(".lua").unwrap_or(name);
+    if hash::stingray_hash64(name.as_bytes()) != name_hash {
+        return None;
+    }
+
+    let path = PathBuf::from(name);
+    if path.components().all(|c| matches!(c, Component::Normal(_))) {
+        Some(path)
+    } else {
+        None
+    }
+}
+
+pub fn export(resource: &Resource) -> io::Result<(PathBuf, Vec<u8>)> {
+    let variant = resource.variants.first()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "lua resource without data"))?;
+
+    Ok(match body(variant.data) {
+        Body::Bytecode(data) => {
+            let mut path = chunk_name(data)
+                .and_then(|chunk| chunk_path(chunk, resource.name_hash))
+                .unwrap_or_else(|| PathBuf::from(format!("{:016x}", resource.name_hash)))
+                .into_os_string();
+            // not with_extension, chunk names may contain dots
+            path.push(".luac");
+            (PathBuf::from(path), data.to_vec())
+        }
+        Body::Source(data) => {
+            (PathBuf::from(format!("{:016x}.lua", resource.name_hash)), data.to_vec())
+        }
+    })
+}
+
+// Wrap lua source or bytecode into resource data, keeping the unknown header
+// words of the original resource if there is one.
+pub fn import(name_hash: u64, payload: &[u8], original: Option<&[u8]>) -> io::Result<Vec<u8>> {
+    let mut unknown = [0; HEADER_SIZE - 4];
+    if let Some(original) = original {
+        let original = Resource::parse(original)?;
+        if let Some(variant) = original.variants.first()
+            && has_header(variant.data)
+        {
+            unknown.copy_from_slice(&variant.data[4..HEADER_SIZE]);
+        }
+    }
+
+    let size = u32::try_from(payload.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "lua file too large"))?;
+    let mut data = Vec::with_capacity(HEADER_SIZE + payload.len());
+    data.write_u32::<LE>(size).unwrap();
+    data.extend(unknown);
+    data.extend(payload);
+    Ok(resource::wrap(EXT_HASH, name_hash, &data))
+}
+
+pub fn is_lua(path: &Path) -> bool {
+    matches!(path.extension().and_then(|ext| ext.to_str()), Some("lua" | "luac"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunk_name_from_bytecode() {
+        let mut dump = b"\x1bLJ\x02\x00".to_vec();
+        dump.push(16);
+        dump.extend(b"@scripts/foo.lua");
+        dump.extend(&[0, 0, 0]);
+        assert_eq!(chunk_name(&dump), Some("@scripts/foo.lua"));
+
+        // stripped bytecode has no chunk name
+        assert_eq!(chunk_name(b"\x1bLJ\x02\x02\x00"), None);
+    }
+
+    #[test]
+    fn import_export_roundtrip() {
+        let name_hash = hash::stingray_hash64(b"scripts/foo");
+        let data = import(name_hash, b"print(1)", None).unwrap();
+        let resource = Resource::parse(&data).unwrap();
+        let (path, payload) = export(&resource).unwrap();
+        assert_eq!(payload, b"print(1)");
+        assert_eq!(path, PathBuf::from(format!("{name_hash:016x}.lua")));
+    }
+
+    #[test]
+    fn dotted_chunk_name_roundtrip() {
+        let name_hash = hash::stingray_hash64(b"scripts/ui/views/hud.v2");
+        let mut dump = b"\x1bLJ\x02\x00".to_vec();
+        dump.push(28);
+        dump.extend(b"@scripts/ui/views/hud.v2.lua");
+        dump.extend(&[0, 0, 0]);
+        let data = import(name_hash, &dump, None).unwrap();
+        let (path, payload) = export(&Resource::parse(&data).unwrap()).unwrap();
+        assert_eq!(path, PathBuf::from("scripts/ui/views/hud.v2.luac"));
+        assert_eq!(payload, dump);
+        assert_eq!(crate::convert::import_key(&path), Some((name_hash, EXT_HASH)));
+    }
+
+    #[test]
+    fn import_keeps_header_words() {
+        let name_hash = hash::stingray_hash64(b"scripts/foo");
+        let mut original = 3u32.to_le_bytes().to_vec();
+        original.extend(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        original.extend(b"old");
+        let original = resource::wrap(EXT_HASH, name_hash, &original);
+
+        let data = import(name_hash, b"print(1)", Some(&original)).unwrap();
+        let resource = Resource::parse(&data).unwrap();
+        let variant = &resource.variants[0].data;
+        assert_eq!(&variant[..4], &8u32.to_le_bytes());
+        assert_eq!(&variant[4..HEADER_SIZE], &[1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(&variant[HEADER_SIZE..], b"print(1)");
+    }
+}
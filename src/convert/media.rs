@@ -0,0 +1,219 @@
+use std::fmt::Write;
+use std::io;
+use std::path::PathBuf;
+use byteorder::ReadBytesExt;
+use byteorder::LE;
+
+use super::ReadSeek;
+use crate::resource::Resource;
+
+pub const WWISE_BANK: u64 = 0x535a7bd3e650d799;
+pub const WWISE_STREAM: u64 = 0x504b55235d21440e;
+pub const BIK: u64 = 0xaa5965f03029fa18;
+pub const IVF: u64 = 0xfa4a8e091a91201e;
+
+// furthest offset the media data is searched for behind the Stingray header
+const MAX_PREAMBLE: usize = 0x100;
+
+pub struct Kind {
+    pub ext_hash: u64,
+    // resource extension followed by media extension, so converted files
+    // never clash with unpacked `bik` and `ivf` resources
+    pub suffix: &'static str,
+    magics: &'static [&'static [u8]],
+}
+
+pub const KINDS: [Kind; 4] = [
+    Kind {
+        ext_hash: WWISE_BANK,
+        suffix: ".wwise_bank.bnk",
+        magics: &[b"BKHD"],
+    },
+    Kind {
+        ext_hash: WWISE_STREAM,
+        suffix: ".wwise_stream.wem",
+        magics: &[b"RIFF", b"RIFX"],
+    },
+    Kind {
+        ext_hash: BIK,
+        suffix: ".bik.bik",
+        magics: &[b"BIK", b"KB2"],
+    },
+    Kind {
+        ext_hash: IVF,
+        suffix: ".ivf.ivf",
+        magics: &[b"DKIF"],
+    },
+];
+
+impl Kind {
+    fn media_ext(&self) -> &'static str {
+        &self.suffix[self.suffix.rfind('.').unwrap() + 1..]
+    }
+}
+
+pub fn kind(ext_hash: u64) -> Option<&'static Kind> {
+    KINDS.iter().find(|kind| kind.ext_hash == ext_hash)
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn find_magic(kind: &Kind, data: &[u8]) -> Option<usize> {
+    let data = &data[..data.len().min(MAX_PREAMBLE + 4)];
+    kind.magics.iter()
+        .filter_map(|magic| data.windows(magic.len()).position(|w| w == *magic))
+        .min()
+}
+
+// Offset of media data in the first variant, or the variant length if the
+// media data is entirely in the .stream file.
+fn payload_offset(kind: &Kind, resource: &Resource, stream: &[u8]) -> io::Result<usize> {
+    let variant = resource.variants.first()
+        .ok_or_else(|| invalid("media resource without data"))?;
+    if let Some(offset) = find_magic(kind, variant.data) {
+        Ok(offset)
+    } else if find_magic(kind, stream) == Some(0) {
+        Ok(variant.data.len())
+    } else {
+        Err(invalid(&format!("no {} data in resource {:016x}", kind.media_ext(), resource.name_hash)))
+    }
+}
+
+// WEM files embedded in a sound bank as (id, offset, size) from its DIDX
+// chunk. Offsets are relative to the bank's DATA chunk.
+pub fn bank_wems(bank: &[u8]) -> io::Result<Vec<(u32, u32, u32)>> {
+    let mut out = Vec::new();
+    let mut rdr = bank;
+    while rdr.len() >= 8 {
+        let (tag, rest) = rdr.split_at(4);
+        rdr = rest;
+        let size = rdr.read_u32::<LE>()? as usize;
+        let chunk = rdr.get(..size).ok_or_else(|| invalid("sound bank chunk runs past end of file"))?;
+        rdr = &rdr[size..];
+        if tag == b"DIDX" {
+            let mut entries = chunk;
+            while entries.len() >= 12 {
+                out.push((
+                    entries.read_u32::<LE>()?,
+                    entries.read_u32::<LE>()?,
+                    entries.read_u32::<LE>()?,
+                ));
+            }
+        }
+    }
+    Ok(out)
+}
+
+// Export media payload, together with a listing of embedded WEM files for
+// sound banks.
+pub fn export(
+    kind: &Kind,
+    resource: &Resource,
+    stream: Option<&mut dyn ReadSeek>,
+) -> io::Result<Vec<(PathBuf, Vec<u8>)>> {
+    let variant = resource.variants.first()
+        .ok_or_else(|| invalid("media resource without data"))?;
+    let streamed = super::read_stream(resource, variant, stream)?;
+    let offset = payload_offset(kind, resource, &streamed)?;
+
+    let mut payload = variant.data[offset..].to_vec();
+    payload.extend(streamed);
+
+    let path = PathBuf::from(format!("{:016x}{}", resource.name_hash, kind.suffix));
+    let mut out = Vec::with_capacity(2);
+    // the listing is extra, banks laid out differently are still exported
+    let wems = match kind.ext_hash {
+        WWISE_BANK => bank_wems(&payload)
+            .inspect_err(|e| eprintln!("warning: no WEM listing for {}: {e}", path.display()))
+            .ok(),
+        _ => None,
+    };
+    if let Some(wems) = wems {
+        let mut listing = String::from("{\n  \"wems\": [");
+        for (i, (id, offset, size)) in wems.into_iter().enumerate() {
+            listing.push_str(if i > 0 { ",\n    " } else { "\n    " });
+            write!(listing, "{{\"id\": {id}, \"offset\": {offset}, \"size\": {size}}}").unwrap();
+        }
+        listing.push_str("\n  ]\n}\n");
+        let mut listing_path = path.clone().into_os_string();
+        listing_path.push(".json");
+        out.push((PathBuf::from(listing_path), listing.into_bytes()));
+    }
+    out.insert(0, (path, payload));
+    Ok(out)
+}
+
+// Rebuild media resource around replacement file, reusing the Stingray header
+// of the original resource.
+pub fn import(kind: &Kind, original: &[u8], payload: &[u8]) -> io::Result<Vec<u8>> {
+    if find_magic(kind, payload) != Some(0) {
+        return Err(invalid(&format!("replacement is not a {} file", kind.media_ext())));
+    }
+
+    let original = Resource::parse(original)?;
+    let variant = original.variants.first()
+        .ok_or_else(|| invalid("media resource without data"))?;
+    // without the .stream file the payload is assumed to start in the resource
+    let offset = find_magic(kind, variant.data).unwrap_or(variant.data.len());
+    let old_size = variant.data.len() - offset + variant.stream_size as usize;
+    super::replace_payload(&original, offset, old_size, payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resource::Variant;
+
+    #[test]
+    fn import_keeps_other_variants_and_header() {
+        // header word equal to the old size that is not the size field
+        let mut header = 10u32.to_le_bytes().to_vec();
+        header.extend(10u32.to_le_bytes());
+        let mut data = header.clone();
+        data.extend(b"BIKi");
+        data.extend([1; 6]);
+        let other = b"BIKi other".to_vec();
+        let original = Resource {
+            ext_hash: BIK,
+            name_hash: 1,
+            flags: 0,
+            stream_offset: 0,
+            variants: vec![
+                Variant { language: 0, stream_size: 0, data: &data },
+                Variant { language: 5, stream_size: 0, data: &other },
+            ],
+        }.to_vec();
+
+        let imported = import(kind(BIK).unwrap(), &original, b"BIKi replaced!").unwrap();
+        let resource = Resource::parse(&imported).unwrap();
+        assert_eq!(resource.variants.len(), 2);
+        assert_eq!(resource.variants[0].data[..4], 10u32.to_le_bytes());
+        assert_eq!(resource.variants[0].data[4..8], 14u32.to_le_bytes());
+        assert_eq!(&resource.variants[0].data[8..], b"BIKi replaced!");
+        assert_eq!((resource.variants[1].language, resource.variants[1].data), (5, &other[..]));
+    }
+
+    #[test]
+    fn export_bank_with_unexpected_layout() {
+        let mut data = b"BKHD".to_vec();
+        data.extend(4u32.to_le_bytes());
+        data.extend([0; 4]);
+        // DIDX chunk claiming more data than the bank has
+        data.extend(b"DIDX");
+        data.extend(100u32.to_le_bytes());
+        let resource = Resource {
+            ext_hash: WWISE_BANK,
+            name_hash: 1,
+            flags: 0,
+            stream_offset: 0,
+            variants: vec![Variant { language: 0, stream_size: 0, data: &data }],
+        };
+
+        let files = export(kind(WWISE_BANK).unwrap(), &resource, None).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].0, PathBuf::from("0000000000000001.wwise_bank.bnk"));
+        assert_eq!(files[0].1, data);
+    }
+}
@@ -0,0 +1,272 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::hash;
+use crate::resource;
+use crate::resource::Resource;
+use crate::resource::Variant;
+
+pub mod lua;
+pub mod media;
+pub mod strings;
+pub mod texture;
+
+pub trait ReadSeek: Read + Seek {}
+impl<T: Read + Seek> ReadSeek for T {}
+
+// Resource kinds to convert into plain files when unpacking.
+#[derive(Clone, Copy, Default)]
+pub struct Convert {
+    pub lua: bool,
+    pub textures: bool,
+    pub strings: bool,
+    // write strings as csv instead of json
+    pub strings_csv: bool,
+    // wwise banks and streams, bink and ivf videos
+    pub media: bool,
+}
+
+impl Convert {
+    // Parse comma separated list of kinds (for example "lua,textures,media").
+    pub fn parse(list: &str) -> Result<Self, String> {
+        let mut out = Self::default();
+        for kind in list.split(',').map(str::trim).filter(|kind| !kind.is_empty()) {
+            match kind {
+                "lua" => out.lua = true,
+                "textures" | "texture" => out.textures = true,
+                "strings" => out.strings = true,
+                "strings-csv" => {
+                    out.strings = true;
+                    out.strings_csv = true;
+                }
+                "media" => out.media = true,
+                "all" => {
+                    out.lua = true;
+                    out.textures = true;
+                    out.strings = true;
+                    out.media = true;
+                }
+                kind => return Err(format!("unknown conversion \"{kind}\"")),
+            }
+        }
+        Ok(out)
+    }
+
+    pub fn any(&self) -> bool {
+        self.lua || self.textures || self.strings || self.media
+    }
+}
+
+// Bundle specific data used by conversions.
+#[derive(Default)]
+pub struct Context<'a> {
+    // bundle's .stream file
+    pub stream: Option<&'a mut dyn ReadSeek>,
+    // names of string keys by 32 bit hash
+    pub string_keys: Option<&'a HashMap<u32, String>>,
+}
+
+// Read data of variant stored in the bundle's .stream file.
+fn read_stream(
+    resource: &Resource,
+    variant: &Variant,
+    stream: Option<&mut dyn ReadSeek>,
+) -> io::Result<Vec<u8>> {
+    if variant.stream_size == 0 {
+        return Ok(Vec::new());
+    }
+
+    let stream = stream.ok_or_else(|| io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("resource {:016x} has data in missing .stream file", resource.name_hash),
+    ))?;
+    stream.seek(SeekFrom::Start(resource.stream_offset as u64))?;
+    let mut out = vec![0; variant.stream_size as usize];
+    stream.read_exact(&mut out)?;
+    Ok(out)
+}
+
+// Rebuild resource with `payload` behind the first `offset` bytes (the
+// Stingray header) of the original's first variant, the variant that is
+// exported. Its data is stored in the resource so its reference into the
+// .stream file is dropped, other language variants are kept as they are.
+// The payload's size is the u32 right before it, which is updated if it
+// holds `old_size`.
+fn replace_payload(original: &Resource, offset: usize, old_size: usize, payload: &[u8]) -> io::Result<Vec<u8>> {
+    let (variant, others) = original.variants.split_first()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "resource without data"))?;
+    let old_size = u32::try_from(old_size).unwrap();
+    let new_size = u32::try_from(payload.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "replacement too large"))?;
+
+    let mut data = variant.data[..offset].to_vec();
+    if offset >= 4 && data[offset - 4..] == old_size.to_le_bytes() {
+        data[offset - 4..].copy_from_slice(&new_size.to_le_bytes());
+    }
+    data.extend(payload);
+
+    let mut variants = vec![Variant {
+        language: variant.language,
+        stream_size: 0,
+        data: &data,
+    }];
+    variants.extend(others.iter().map(|other| Variant {
+        language: other.language,
+        stream_size: other.stream_size,
+        data: other.data,
+    }));
+    // other variants may still read from the .stream file
+    let streamed = others.iter().any(|other| other.stream_size > 0);
+    Ok(Resource {
+        ext_hash: original.ext_hash,
+        name_hash: original.name_hash,
+        flags: original.flags,
+        stream_offset: if streamed { original.stream_offset } else { 0 },
+        variants,
+    }.to_vec())
+}
+
+pub struct Exported {
+    // (path relative to unpacked directory, data) of converted files
+    pub files: Vec<(PathBuf, Vec<u8>)>,
+    // also write unpacked resource since import needs it to rebuild the resource
+    pub keep_unpacked: bool,
+}
+
+// Convert unpacked resource into a plain file.
+//
+// Returns None if the resource kind is not selected for conversion.
+pub fn export(convert: &Convert, data: &[u8], context: Context) -> io::Result<Option<Exported>> {
+    if !convert.any() {
+        return Ok(None);
+    }
+
+    let resource = Resource::parse(data)?;
+    let (files, keep_unpacked) = match resource.ext_hash {
+        lua::EXT_HASH if convert.lua => (vec![lua::export(&resource)?], false),
+        texture::EXT_HASH if convert.textures => {
+            (vec![texture::export(&resource, context.stream)?], true)
+        }
+        strings::EXT_HASH if convert.strings => {
+            (vec![strings::export(&resource, convert.strings_csv, context.string_keys)?], false)
+        }
+        ext_hash if convert.media && let Some(kind) = media::kind(ext_hash) => {
+            (media::export(kind, &resource, context.stream)?, true)
+        }
+        _ => return Ok(None),
+    };
+
+    Ok(Some(Exported {
+        files,
+        keep_unpacked,
+    }))
+}
+
+// Extension hash and file name suffix of converted file.
+fn import_target(path: &Path) -> Option<(u64, &'static str)> {
+    let name = path.file_name()?.to_str()?;
+    [
+        (lua::EXT_HASH, ".lua"),
+        (lua::EXT_HASH, ".luac"),
+        (texture::EXT_HASH, ".dds"),
+        (strings::EXT_HASH, strings::JSON_SUFFIX),
+        (strings::EXT_HASH, strings::CSV_SUFFIX),
+    ].into_iter()
+        .chain(media::KINDS.iter().map(|kind| (kind.ext_hash, kind.suffix)))
+        .find(|(_, suffix)| name.len() > suffix.len() && name.ends_with(suffix))
+}
+
+pub fn is_importable(path: &Path) -> bool {
+    import_target(path).is_some()
+}
+
+// Derive (name_hash, ext_hash) of converted file from its path relative to
+// the unpacked directory. Top level files named after a 16 digit hex hash use
+// that hash, otherwise the path without extension is hashed.
+pub fn import_key(relative: &Path) -> Option<(u64, u64)> {
+    let (ext_hash, suffix) = import_target(relative)?;
+
+    let mut name = String::new();
+    for component in relative.components() {
+        match component {
+            Component::Normal(part) => {
+                if !name.is_empty() {
+                    name.push('/');
+                }
+                name.push_str(part.to_str()?);
+            }
+            _ => return None,
+        }
+    }
+    name.truncate(name.len() - suffix.len());
+
+    let name_hash = if name.len() == 16 && !name.contains('/') {
+        u64::from_str_radix(&name, 16).ok()
+    } else {
+        None
+    }.unwrap_or_else(|| hash::stingray_hash64(name.as_bytes()));
+
+    Some((name_hash, ext_hash))
+}
+
+// Build resource data for `file` from a file given by the user. Unpacked
+// resources are used as they are, converted files are imported and anything
+// else becomes the payload of a single variant.
+pub fn wrap_file(
+    file: (u64, u64),
+    path: &Path,
+    data: &[u8],
+    original: Option<&[u8]>,
+) -> io::Result<Vec<u8>> {
+    if resource::is_wrapped(data, file.1) {
+        let mut resource = Resource::parse(data)?;
+        resource.name_hash = file.0;
+        return Ok(resource.to_vec());
+    }
+
+    match import_target(path) {
+        Some((ext_hash, _)) if ext_hash == file.1 => import(file, path, data, original),
+        _ if file.1 == lua::EXT_HASH => lua::import(file.0, data, original),
+        _ => Ok(resource::wrap(file.1, file.0, data)),
+    }
+}
+
+// Wrap converted file back into resource data with srpack's index prefix.
+//
+// `path` is the converted file and `original` the unpacked resource it was
+// converted from, if any.
+pub fn import(
+    file: (u64, u64),
+    path: &Path,
+    data: &[u8],
+    original: Option<&[u8]>,
+) -> io::Result<Vec<u8>> {
+    match file.1 {
+        lua::EXT_HASH => lua::import(file.0, data, original),
+        strings::EXT_HASH => strings::import(file.0, path, data),
+        texture::EXT_HASH => match original {
+            Some(original) => texture::import(original, data),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("replacing texture {:016x} requires its unpacked .texture file", file.0),
+            )),
+        },
+        ext_hash if let Some(kind) = media::kind(ext_hash) => match original {
+            Some(original) => media::import(kind, original, data),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("replacing media {:016x} requires its unpacked resource", file.0),
+            )),
+        },
+        ext_hash => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("no conversion for extension hash {ext_hash:016x}"),
+        )),
+    }
+}
@@ -0,0 +1,351 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use byteorder::ReadBytesExt;
+use byteorder::WriteBytesExt;
+use byteorder::LE;
+
+use crate::hash;
+use crate::json;
+use crate::resource::Resource;
+use crate::resource::Variant;
+
+pub const EXT_HASH: u64 = 0x0d972bab10b40fd3;
+
+pub const JSON_SUFFIX: &str = ".strings.json";
+pub const CSV_SUFFIX: &str = ".strings.csv";
+
+// Localized strings of every variant, keyed by 32 bit hash of the string key
+// (upper half of the murmur hash). Each variant of the resource holds:
+//
+//     count: u32
+//     count * (key: u32, offset: u32)
+//     null terminated utf8 strings, offset relative to start of variant
+pub struct Table {
+    pub languages: Vec<u32>,
+    // (key, text in each of `languages`)
+    pub entries: Vec<(u32, Vec<Option<String>>)>,
+}
+
+pub fn key_hash(key: &str) -> u32 {
+    (hash::stingray_hash64(key.as_bytes()) >> 32) as u32
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn decode_variant(data: &[u8]) -> io::Result<Vec<(u32, String)>> {
+    let mut rdr = data;
+    let count = rdr.read_u32::<LE>()? as usize;
+    let mut out = Vec::with_capacity(count.min(data.len() / 8));
+    for _ in 0..count {
+        let key = rdr.read_u32::<LE>()?;
+        let offset = rdr.read_u32::<LE>()? as usize;
+        let text = data.get(offset..).ok_or_else(|| invalid("string offset out of bounds"))?;
+        let end = text.iter().position(|b| *b == 0).ok_or_else(|| invalid("unterminated string"))?;
+        let text = String::from_utf8(text[..end].to_vec()).map_err(|_| invalid("invalid utf8 in string"))?;
+        out.push((key, text));
+    }
+    Ok(out)
+}
+
+fn encode_variant(entries: &[(u32, &str)]) -> Vec<u8> {
+    let table_size = 4 + entries.len() * 8;
+    let mut out = Vec::with_capacity(table_size + entries.iter().map(|(_, s)| s.len() + 1).sum::<usize>());
+    out.write_u32::<LE>(u32::try_from(entries.len()).unwrap()).unwrap();
+    let mut offset = table_size;
+    for (key, text) in entries.iter() {
+        out.write_u32::<LE>(*key).unwrap();
+        out.write_u32::<LE>(u32::try_from(offset).unwrap()).unwrap();
+        offset += text.len() + 1;
+    }
+    for (_, text) in entries.iter() {
+        out.extend(text.as_bytes());
+        out.push(0);
+    }
+    out
+}
+
+pub fn decode(resource: &Resource) -> io::Result<Table> {
+    let mut table = Table {
+        languages: Vec::with_capacity(resource.variants.len()),
+        entries: Vec::new(),
+    };
+    let mut lookup = HashMap::new();
+    let num_languages = resource.variants.len();
+    for (i, variant) in resource.variants.iter().enumerate() {
+        table.languages.push(variant.language);
+        for (key, text) in decode_variant(variant.data)? {
+            let index = *lookup.entry(key).or_insert_with(|| {
+                table.entries.push((key, vec![None; num_languages]));
+                table.entries.len() - 1
+            });
+            table.entries[index].1[i] = Some(text);
+        }
+    }
+    Ok(table)
+}
+
+pub fn encode(table: &Table, name_hash: u64) -> Vec<u8> {
+    let variants = table.languages.iter().enumerate()
+        .map(|(i, _)| {
+            let entries = table.entries.iter()
+                .filter_map(|(key, texts)| texts[i].as_deref().map(|text| (*key, text)))
+                .collect::<Vec<_>>();
+            encode_variant(&entries)
+        })
+        .collect::<Vec<_>>();
+
+    Resource {
+        ext_hash: EXT_HASH,
+        name_hash,
+        flags: 0,
+        stream_offset: 0,
+        variants: table.languages.iter().zip(variants.iter())
+            .map(|(language, data)| Variant {
+                language: *language,
+                stream_size: 0,
+                data,
+            })
+            .collect(),
+    }.to_vec()
+}
+
+pub fn to_json(table: &Table, names: Option<&HashMap<u32, String>>) -> String {
+    let mut out = String::new();
+    out.push_str("{\n  \"languages\": [");
+    for (i, language) in table.languages.iter().enumerate() {
+        if i > 0 {
+            out.push_str(", ");
+        }
+        write!(out, "\"{language:08x}\"").unwrap();
+    }
+    out.push_str("],\n  \"entries\": [");
+    for (i, (key, texts)) in table.entries.iter().enumerate() {
+        out.push_str(if i > 0 { ",\n    {" } else { "\n    {" });
+        write!(out, "\"key\": \"{key:08x}\"").unwrap();
+        if let Some(name) = names.and_then(|names| names.get(key)) {
+            write!(out, ", \"name\": {}", json::quote(name)).unwrap();
+        }
+        out.push_str(", \"text\": {");
+        let mut first = true;
+        for (language, text) in table.languages.iter().zip(texts.iter()) {
+            if let Some(text) = text {
+                if !first {
+                    out.push_str(", ");
+                }
+                first = false;
+                write!(out, "\"{language:08x}\": {}", json::quote(text)).unwrap();
+            }
+        }
+        out.push_str("}}");
+    }
+    out.push_str("\n  ]\n}\n");
+    out
+}
+
+fn parse_hex32(s: &str) -> io::Result<u32> {
+    let s = s.strip_prefix("0x").unwrap_or(s);
+    u32::from_str_radix(s, 16).map_err(|_| invalid(&format!("invalid hash \"{s}\"")))
+}
+
+// Key of entry from hex hash, falling back to hashing the name.
+fn entry_key(key: Option<&str>, name: Option<&str>) -> io::Result<u32> {
+    match (key, name) {
+        (Some(key), _) if !key.is_empty() => parse_hex32(key),
+        (_, Some(name)) if !name.is_empty() => Ok(key_hash(name)),
+        _ => Err(invalid("string entry without key or name")),
+    }
+}
+
+pub fn from_json(data: &[u8]) -> io::Result<Table> {
+    let value = json::parse(data)?;
+    let languages = value.get("languages")
+        .and_then(|l| l.as_array())
+        .ok_or_else(|| invalid("missing \"languages\""))?
+        .iter()
+        .map(|l| l.as_str().ok_or_else(|| invalid("language is not a string")).and_then(parse_hex32))
+        .collect::<io::Result<Vec<_>>>()?;
+
+    let mut entries = Vec::new();
+    for entry in value.get("entries").and_then(|e| e.as_array()).ok_or_else(|| invalid("missing \"entries\""))? {
+        let key = entry_key(
+            entry.get("key").and_then(|k| k.as_str()),
+            entry.get("name").and_then(|n| n.as_str()),
+        )?;
+        let mut texts = vec![None; languages.len()];
+        for (language, text) in entry.get("text").and_then(|t| t.as_object()).unwrap_or(&[]) {
+            let language = parse_hex32(language)?;
+            let i = languages.iter().position(|l| *l == language)
+                .ok_or_else(|| invalid(&format!("language {language:08x} not in \"languages\"")))?;
+            texts[i] = Some(text.as_str().ok_or_else(|| invalid("text is not a string"))?.to_string());
+        }
+        entries.push((key, texts));
+    }
+
+    Ok(Table {
+        languages,
+        entries,
+    })
+}
+
+fn csv_field(out: &mut String, field: &str) {
+    if field.is_empty() || field.contains([',', '"', '\n', '\r']) {
+        out.push('"');
+        out.push_str(&field.replace('"', "\"\""));
+        out.push('"');
+    } else {
+        out.push_str(field);
+    }
+}
+
+// Columns: key, name, then one column per language. Missing text is written
+// as an empty field and empty text as "".
+pub fn to_csv(table: &Table, names: Option<&HashMap<u32, String>>) -> String {
+    let mut out = String::from("key,name");
+    for language in table.languages.iter() {
+        write!(out, ",{language:08x}").unwrap();
+    }
+    out.push('\n');
+    for (key, texts) in table.entries.iter() {
+        write!(out, "{key:08x},").unwrap();
+        if let Some(name) = names.and_then(|names| names.get(key)) {
+            csv_field(&mut out, name);
+        }
+        for text in texts.iter() {
+            out.push(',');
+            if let Some(text) = text {
+                csv_field(&mut out, text);
+            }
+        }
+        out.push('\n');
+    }
+    out
+}
+
+// Records of csv fields, None for empty fields without quotes.
+fn csv_records(data: &str) -> io::Result<Vec<Vec<Option<String>>>> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut chars = data.chars().peekable();
+    let mut quoted = false;
+    let mut was_quoted = false;
+    let end_field = |field: &mut String, was_quoted: &mut bool| {
+        let field = std::mem::take(field);
+        let was_quoted = std::mem::take(was_quoted);
+        (!field.is_empty() || was_quoted).then_some(field)
+    };
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted => {
+                if chars.peek() == Some(&'"') {
+                    chars.next();
+                    field.push('"');
+                } else {
+                    quoted = false;
+                }
+            }
+            '"' if field.is_empty() => {
+                quoted = true;
+                was_quoted = true;
+            }
+            ',' if !quoted => record.push(end_field(&mut field, &mut was_quoted)),
+            '\r' if !quoted && chars.peek() == Some(&'\n') => {}
+            '\n' if !quoted => {
+                record.push(end_field(&mut field, &mut was_quoted));
+                records.push(std::mem::take(&mut record));
+            }
+            c => field.push(c),
+        }
+    }
+    if quoted {
+        return Err(invalid("unterminated quoted csv field"));
+    }
+    if !field.is_empty() || was_quoted || !record.is_empty() {
+        record.push(end_field(&mut field, &mut was_quoted));
+        records.push(record);
+    }
+    Ok(records)
+}
+
+pub fn from_csv(data: &[u8]) -> io::Result<Table> {
+    let data = std::str::from_utf8(data).map_err(|_| invalid("invalid utf8 in csv"))?;
+    let mut records = csv_records(data)?.into_iter();
+    let header = records.next().ok_or_else(|| invalid("empty csv"))?;
+    if header.len() < 2 {
+        return Err(invalid("csv header must start with key,name"));
+    }
+    let languages = header[2..].iter()
+        .map(|l| parse_hex32(l.as_deref().unwrap_or("")))
+        .collect::<io::Result<Vec<_>>>()?;
+
+    let mut entries = Vec::new();
+    for record in records {
+        let key = entry_key(
+            record.first().and_then(|k| k.as_deref()),
+            record.get(1).and_then(|n| n.as_deref()),
+        )?;
+        let texts = (0..languages.len())
+            .map(|i| record.get(i + 2).cloned().flatten())
+            .collect();
+        entries.push((key, texts));
+    }
+
+    Ok(Table {
+        languages,
+        entries,
+    })
+}
+
+pub fn export(resource: &Resource, csv: bool, names: Option<&HashMap<u32, String>>) -> io::Result<(PathBuf, Vec<u8>)> {
+    let table = decode(resource)?;
+    Ok(if csv {
+        (PathBuf::from(format!("{:016x}{CSV_SUFFIX}", resource.name_hash)), to_csv(&table, names).into_bytes())
+    } else {
+        (PathBuf::from(format!("{:016x}{JSON_SUFFIX}", resource.name_hash)), to_json(&table, names).into_bytes())
+    })
+}
+
+pub fn import(name_hash: u64, path: &Path, data: &[u8]) -> io::Result<Vec<u8>> {
+    let table = if path.to_string_lossy().ends_with(CSV_SUFFIX) {
+        from_csv(data)?
+    } else {
+        from_json(data)?
+    };
+    Ok(encode(&table, name_hash))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table() -> Table {
+        Table {
+            languages: vec![0, 0x6c7a2bbf],
+            entries: vec![
+                (key_hash("menu_title"), vec![Some("Title".into()), Some("Titre, \"grand\"".into())]),
+                (key_hash("menu_quit"), vec![Some("Quit\nnow".into()), None]),
+                (key_hash("menu_empty"), vec![Some(String::new()), None]),
+            ],
+        }
+    }
+
+    #[test]
+    fn json_and_csv_roundtrip() {
+        let data = encode(&table(), 1);
+        let names = HashMap::from([(key_hash("menu_title"), "menu_title".to_string())]);
+        let decoded = decode(&Resource::parse(&data).unwrap()).unwrap();
+
+        let from_json = from_json(to_json(&decoded, Some(&names)).as_bytes()).unwrap();
+        assert_eq!(encode(&from_json, 1), data);
+
+        let from_csv = from_csv(to_csv(&decoded, Some(&names)).as_bytes()).unwrap();
+        assert_eq!(encode(&from_csv, 1), data);
+        let empty = from_csv.entries.iter().find(|(key, _)| *key == key_hash("menu_empty")).unwrap();
+        assert_eq!(empty.1, vec![Some(String::new()), None]);
+    }
+}
@@ -0,0 +1,77 @@
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+
+use super::ReadSeek;
+use crate::resource::Resource;
+
+pub const EXT_HASH: u64 = 0xcd4238c6a0c69e32;
+
+const DDS_MAGIC: &[u8] = b"DDS ";
+// magic + DDS_HEADER
+const DDS_HEADER_SIZE: usize = 128;
+// magic + DDS_HEADER + DDS_HEADER_DXT10
+const DDS_DX10_HEADER_SIZE: usize = 148;
+// furthest offset the DDS data is searched for behind the Stingray header
+const MAX_PREAMBLE: usize = 0x100;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn find_dds(data: &[u8]) -> Option<usize> {
+    let end = data.len().min(MAX_PREAMBLE + DDS_MAGIC.len());
+    data[..end].windows(DDS_MAGIC.len()).position(|w| w == DDS_MAGIC)
+}
+
+fn dds_header_size(dds: &[u8]) -> usize {
+    if dds.get(84..88) == Some(b"DX10") {
+        DDS_DX10_HEADER_SIZE
+    } else {
+        DDS_HEADER_SIZE
+    }
+}
+
+// Export texture as standalone DDS.
+//
+// Larger mip levels of streamed textures live in the bundle's .stream file
+// at `stream_offset` and are placed between the DDS header and the mip
+// levels stored in the resource.
+pub fn export(resource: &Resource, stream: Option<&mut dyn ReadSeek>) -> io::Result<(PathBuf, Vec<u8>)> {
+    let variant = resource.variants.first()
+        .ok_or_else(|| invalid("texture resource without data"))?;
+    let offset = find_dds(variant.data)
+        .ok_or_else(|| invalid("texture resource without DDS data"))?;
+    let dds = &variant.data[offset..];
+    let header_size = dds_header_size(dds);
+    if dds.len() < header_size {
+        return Err(invalid("truncated DDS header"));
+    }
+
+    let mut out = Vec::with_capacity(dds.len() + variant.stream_size as usize);
+    out.extend(&dds[..header_size]);
+    out.extend(super::read_stream(resource, variant, stream)?);
+    out.extend(&dds[header_size..]);
+
+    Ok((PathBuf::from(format!("{:016x}.dds", resource.name_hash)), out))
+}
+
+// Rebuild texture resource around replacement DDS, reusing the Stingray
+// header of the original resource.
+pub fn import(original: &[u8], dds: &[u8]) -> io::Result<Vec<u8>> {
+    if !dds.starts_with(DDS_MAGIC) || dds.len() < dds_header_size(dds) {
+        return Err(invalid("replacement texture is not a DDS file"));
+    }
+
+    let original = Resource::parse(original)?;
+    let variant = original.variants.first()
+        .ok_or_else(|| invalid("texture resource without data"))?;
+    let offset = find_dds(variant.data)
+        .ok_or_else(|| invalid("texture resource without DDS data"))?;
+
+    super::replace_payload(&original, offset, variant.data.len() - offset, dds)
+}
+
+pub fn is_dds(path: &Path) -> bool {
+    path.extension().and_then(|ext| ext.to_str()) == Some("dds")
+}
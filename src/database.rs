@@ -0,0 +1,237 @@
+/// Reader for `bundle_database.data`.
+///
+/// Layout (all little endian), as described by dtmt:
+///
+///     version: u32                    6
+///     num_bundles: u32
+///     num_bundles * {
+///         bundle_hash: u64            name hash of bundle
+///         num_files: u32
+///         num_files * {
+///             file_version: u32       4
+///             name: u32 length + bytes
+///             stream: u32 length + bytes
+///             platform_specific: u8
+///             unknown: [u8; 20]
+///             file_time: u64
+///         }
+///     }
+///     num_hashes: u32
+///     num_hashes * (name_hash: u64, hash: u64)
+///     num_contents: u32
+///     num_contents * {
+///         bundle_hash: u64
+///         num_resources: u32
+///         num_resources * (ext_hash: u64, name_hash: u64)
+///     }
+///
+/// Other versions are rejected rather than guessed at. Until the layout has
+/// been checked against databases shipped with the games, srpack only reads
+/// the file and never writes it.
+use std::fs;
+use std::io;
+use std::path::Path;
+use byteorder::ReadBytesExt;
+use byteorder::LE;
+
+pub const FILE_NAME: &str = "bundle_database.data";
+
+const DATABASE_VERSION: u32 = 6;
+const FILE_VERSION: u32 = 4;
+
+pub struct BundleFile {
+    pub name: String,
+    pub stream: String,
+    pub platform_specific: bool,
+    pub unknown: [u8; 20],
+    pub file_time: u64,
+}
+
+pub struct BundleEntry {
+    pub hash: u64,
+    pub files: Vec<BundleFile>,
+}
+
+pub struct Database {
+    pub bundles: Vec<BundleEntry>,
+    // (name_hash, hash)
+    pub resource_hashes: Vec<(u64, u64)>,
+    // (bundle_hash, [(ext_hash, name_hash)])
+    pub contents: Vec<(u64, Vec<(u64, u64)>)>,
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn read_string(rdr: &mut &[u8]) -> io::Result<String> {
+    let len = rdr.read_u32::<LE>()? as usize;
+    if rdr.len() < len {
+        return Err(invalid("string runs past end of bundle database".to_string()));
+    }
+    let (string, rest) = rdr.split_at(len);
+    *rdr = rest;
+    String::from_utf8(string.to_vec()).map_err(|_| invalid("string in bundle database is not utf-8".to_string()))
+}
+
+// Check count of entries at least min_size bytes each against remaining
+// data, so a misread count fails instead of allocating.
+fn read_count(rdr: &mut &[u8], min_size: usize) -> io::Result<usize> {
+    let count = rdr.read_u32::<LE>()? as usize;
+    if rdr.len() / min_size < count {
+        return Err(invalid(format!("{count} entries run past end of bundle database")));
+    }
+    Ok(count)
+}
+
+impl Database {
+    pub fn parse(data: &[u8]) -> io::Result<Self> {
+        let mut rdr = data;
+        let version = rdr.read_u32::<LE>()?;
+        if version != DATABASE_VERSION {
+            return Err(invalid(format!("unsupported bundle database version {version}")));
+        }
+
+        let num_bundles = read_count(&mut rdr, 12)?;
+        let mut bundles = Vec::with_capacity(num_bundles);
+        for _ in 0..num_bundles {
+            let hash = rdr.read_u64::<LE>()?;
+            let num_files = read_count(&mut rdr, 41)?;
+            let mut files = Vec::with_capacity(num_files);
+            for _ in 0..num_files {
+                let file_version = rdr.read_u32::<LE>()?;
+                if file_version != FILE_VERSION {
+                    return Err(invalid(format!("unsupported file version {file_version} in bundle {hash:016x}")));
+                }
+                let name = read_string(&mut rdr)?;
+                let stream = read_string(&mut rdr)?;
+                let platform_specific = rdr.read_u8()? != 0;
+                let mut unknown = [0; 20];
+                io::Read::read_exact(&mut rdr, &mut unknown)?;
+                let file_time = rdr.read_u64::<LE>()?;
+                files.push(BundleFile {
+                    name,
+                    stream,
+                    platform_specific,
+                    unknown,
+                    file_time,
+                });
+            }
+            bundles.push(BundleEntry { hash, files });
+        }
+
+        let num_hashes = read_count(&mut rdr, 16)?;
+        let mut resource_hashes = Vec::with_capacity(num_hashes);
+        for _ in 0..num_hashes {
+            resource_hashes.push((rdr.read_u64::<LE>()?, rdr.read_u64::<LE>()?));
+        }
+
+        let num_contents = read_count(&mut rdr, 12)?;
+        let mut contents = Vec::with_capacity(num_contents);
+        for _ in 0..num_contents {
+            let hash = rdr.read_u64::<LE>()?;
+            let num_resources = read_count(&mut rdr, 16)?;
+            let mut resources = Vec::with_capacity(num_resources);
+            for _ in 0..num_resources {
+                resources.push((rdr.read_u64::<LE>()?, rdr.read_u64::<LE>()?));
+            }
+            contents.push((hash, resources));
+        }
+
+        if !rdr.is_empty() {
+            return Err(invalid(format!("{} bytes after end of bundle database", rdr.len())));
+        }
+
+        Ok(Self {
+            bundles,
+            resource_hashes,
+            contents,
+        })
+    }
+
+    pub fn read<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::parse(&fs::read(path)?)
+    }
+
+    pub fn find(&self, hash: u64) -> Option<&BundleEntry> {
+        self.bundles.iter().find(|bundle| bundle.hash == hash)
+    }
+
+    // Resources of bundle as (ext_hash, name_hash).
+    pub fn contents(&self, hash: u64) -> &[(u64, u64)] {
+        self.contents.iter()
+            .find(|(bundle, _)| *bundle == hash)
+            .map(|(_, resources)| &resources[..])
+            .unwrap_or(&[])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use byteorder::WriteBytesExt;
+    use super::*;
+
+    fn write_string(data: &mut Vec<u8>, s: &str) {
+        data.write_u32::<LE>(s.len() as u32).unwrap();
+        data.extend(s.as_bytes());
+    }
+
+    fn database() -> Vec<u8> {
+        let mut data = Vec::new();
+        data.write_u32::<LE>(6).unwrap();
+        data.write_u32::<LE>(2).unwrap();
+        for (hash, files) in [(0x1111u64, &["0000000000001111", "0000000000001111.patch_001"][..]), (0x2222, &[])] {
+            data.write_u64::<LE>(hash).unwrap();
+            data.write_u32::<LE>(files.len() as u32).unwrap();
+            for (i, name) in files.iter().enumerate() {
+                data.write_u32::<LE>(4).unwrap();
+                write_string(&mut data, name);
+                write_string(&mut data, &format!("{name}.stream"));
+                data.write_u8(i as u8).unwrap();
+                data.extend([0; 20]);
+                data.write_u64::<LE>(1000 + i as u64).unwrap();
+            }
+        }
+        data.write_u32::<LE>(1).unwrap();
+        data.write_u64::<LE>(2).unwrap();
+        data.write_u64::<LE>(0xabcd).unwrap();
+        data.write_u32::<LE>(1).unwrap();
+        data.write_u64::<LE>(0x1111).unwrap();
+        data.write_u32::<LE>(2).unwrap();
+        for (ext_hash, name_hash) in [(1u64, 2u64), (3, 4)] {
+            data.write_u64::<LE>(ext_hash).unwrap();
+            data.write_u64::<LE>(name_hash).unwrap();
+        }
+        data
+    }
+
+    #[test]
+    fn parse() {
+        let database = Database::parse(&database()).unwrap();
+        assert_eq!(database.bundles.len(), 2);
+        let files = &database.find(0x1111).unwrap().files;
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[1].name, "0000000000001111.patch_001");
+        assert_eq!(files[1].stream, "0000000000001111.patch_001.stream");
+        assert!(files[1].platform_specific);
+        assert_eq!(files[1].file_time, 1001);
+        assert!(database.find(0x2222).unwrap().files.is_empty());
+        assert_eq!(database.resource_hashes, vec![(2, 0xabcd)]);
+        assert_eq!(database.contents(0x1111), &[(1, 2), (3, 4)]);
+        assert!(database.contents(0x2222).is_empty());
+    }
+
+    #[test]
+    fn rejects_what_it_does_not_understand() {
+        let mut data = database();
+        data[0] = 5;
+        assert!(Database::parse(&data).is_err());
+
+        let mut data = database();
+        data.push(0);
+        assert!(Database::parse(&data).is_err());
+
+        let data = database();
+        assert!(Database::parse(&data[..data.len() - 1]).is_err());
+    }
+}
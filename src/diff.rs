@@ -0,0 +1,195 @@
+use std::collections::HashMap;
+
+use crate::resource;
+
+pub enum Change {
+    Added,
+    Removed,
+    // resource data or index flags differ
+    Modified,
+}
+
+pub struct Entry {
+    // (name_hash, ext_hash)
+    pub file: (u64, u64),
+    pub change: Change,
+    // flags and size of resource data (without srpack's index prefix) in a and b
+    pub flags: (Option<u32>, Option<u32>),
+    pub size: (Option<usize>, Option<usize>),
+}
+
+fn body(data: &[u8]) -> &[u8] {
+    &data[resource::PREFIX_SIZE.min(data.len())..]
+}
+
+// Compare unpacked files (as held by Merge::files) of two bundles. Entries
+// are sorted by extension and then name hash.
+pub fn diff(
+    a: &HashMap<(u64, u64), Vec<u8>>,
+    b: &HashMap<(u64, u64), Vec<u8>>,
+) -> Vec<Entry> {
+    let mut out = Vec::new();
+    for (file, a_data) in a.iter() {
+        let a_flags = resource::prefix_flags(a_data);
+        let a_size = Some(body(a_data).len());
+        match b.get(file) {
+            Some(b_data) => {
+                let b_flags = resource::prefix_flags(b_data);
+                if a_flags != b_flags || body(a_data) != body(b_data) {
+                    out.push(Entry {
+                        file: *file,
+                        change: Change::Modified,
+                        flags: (a_flags, b_flags),
+                        size: (a_size, Some(body(b_data).len())),
+                    });
+                }
+            }
+            None => out.push(Entry {
+                file: *file,
+                change: Change::Removed,
+                flags: (a_flags, None),
+                size: (a_size, None),
+            }),
+        }
+    }
+
+    for (file, b_data) in b.iter() {
+        if !a.contains_key(file) {
+            out.push(Entry {
+                file: *file,
+                change: Change::Added,
+                flags: (None, resource::prefix_flags(b_data)),
+                size: (None, Some(body(b_data).len())),
+            });
+        }
+    }
+
+    out.sort_unstable_by_key(|entry| (entry.file.1, entry.file.0));
+    out
+}
+
+// Files of `edited` that are new or differ from `base`. With `tombstone`
+// set, resources missing from `edited` are added as deleted entries with
+// those flags.
+pub fn delta(
+    base: &HashMap<(u64, u64), Vec<u8>>,
+    edited: &HashMap<(u64, u64), Vec<u8>>,
+    tombstone: Option<u32>,
+) -> HashMap<(u64, u64), Vec<u8>> {
+    let mut out = HashMap::new();
+    for entry in diff(base, edited) {
+        let (name_hash, ext_hash) = entry.file;
+        match entry.change {
+            Change::Added | Change::Modified => {
+                out.insert(entry.file, edited[&entry.file].clone());
+            }
+            Change::Removed => if let Some(flags) = tombstone {
+                out.insert(entry.file, resource::tombstone(ext_hash, name_hash, flags));
+            },
+        }
+    }
+    out
+}
+
+pub struct ByteSummary {
+    pub first_difference: Option<usize>,
+    // bytes differing within the common length
+    pub changed: usize,
+    // runs of consecutive differing bytes
+    pub ranges: usize,
+}
+
+pub fn byte_summary(a: &[u8], b: &[u8]) -> ByteSummary {
+    let (a, b) = (body(a), body(b));
+    let mut summary = ByteSummary {
+        first_difference: None,
+        changed: 0,
+        ranges: 0,
+    };
+
+    let mut in_range = false;
+    for (i, (x, y)) in a.iter().zip(b.iter()).enumerate() {
+        if x != y {
+            summary.first_difference.get_or_insert(i);
+            summary.changed += 1;
+            if !in_range {
+                summary.ranges += 1;
+                in_range = true;
+            }
+        } else {
+            in_range = false;
+        }
+    }
+
+    if summary.first_difference.is_none() && a.len() != b.len() {
+        summary.first_difference = Some(a.len().min(b.len()));
+    }
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LUA: u64 = 0xa14e8dfa2cd117e2;
+
+    type Files = HashMap<(u64, u64), Vec<u8>>;
+
+    fn file(name_hash: u64, flags: u32, payload: &[u8]) -> ((u64, u64), Vec<u8>) {
+        let mut data = resource::wrap(LUA, name_hash, payload);
+        data[16..20].copy_from_slice(&flags.to_le_bytes());
+        ((name_hash, LUA), data)
+    }
+
+    fn files() -> (Files, Files) {
+        let a = HashMap::from([file(1, 0, b"same"), file(2, 0, b"removed"), file(3, 0, b"old"), file(4, 0, b"flags")]);
+        let b = HashMap::from([file(1, 0, b"same"), file(3, 0, b"new!"), file(4, 4, b"flags"), file(5, 0, b"added")]);
+        (a, b)
+    }
+
+    #[test]
+    fn changes() {
+        let (a, b) = files();
+        let entries = diff(&a, &b);
+        assert_eq!(entries.iter().map(|entry| entry.file.0).collect::<Vec<_>>(), vec![2, 3, 4, 5]);
+        assert!(matches!(entries[0].change, Change::Removed));
+        assert_eq!((entries[0].flags, entries[0].size), ((Some(0), None), (Some(43), None)));
+        assert!(matches!(entries[1].change, Change::Modified));
+        assert_eq!(entries[1].size, (Some(39), Some(40)));
+        // flags alone count as a modification
+        assert!(matches!(entries[2].change, Change::Modified));
+        assert_eq!((entries[2].flags, entries[2].size), ((Some(0), Some(4)), (Some(41), Some(41))));
+        assert!(matches!(entries[3].change, Change::Added));
+        assert_eq!(entries[3].flags, (None, Some(0)));
+    }
+
+    #[test]
+    fn delta_with_and_without_tombstones() {
+        let (a, b) = files();
+        let out = delta(&a, &b, None);
+        let mut keys = out.keys().map(|file| file.0).collect::<Vec<_>>();
+        keys.sort_unstable();
+        assert_eq!(keys, vec![3, 4, 5]);
+        assert_eq!(out[&(3, LUA)], b[&(3, LUA)]);
+
+        let out = delta(&a, &b, Some(2));
+        assert_eq!(out.len(), 4);
+        assert_eq!(out[&(2, LUA)], resource::tombstone(LUA, 2, 2));
+        assert_eq!(resource::prefix_flags(&out[&(2, LUA)]), Some(2));
+    }
+
+    #[test]
+    fn bytes() {
+        let prefix = [0; resource::PREFIX_SIZE];
+        let with_prefix = |body: &[u8]| [&prefix[..], body].concat();
+        let summary = byte_summary(&with_prefix(b"abcdefgh"), &with_prefix(b"aXcdYZgh"));
+        assert_eq!((summary.first_difference, summary.changed, summary.ranges), (Some(1), 3, 2));
+
+        let summary = byte_summary(&with_prefix(b"abc"), &with_prefix(b"abcdef"));
+        assert_eq!((summary.first_difference, summary.changed, summary.ranges), (Some(3), 0, 0));
+
+        // only the index prefix differs
+        let summary = byte_summary(&with_prefix(b"abc"), &[&[1; resource::PREFIX_SIZE][..], b"abc"].concat());
+        assert_eq!((summary.first_difference, summary.changed, summary.ranges), (None, 0, 0));
+    }
+}
@@ -0,0 +1,263 @@
+/// Structural checks of bundles that report problems instead of panicking.
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+use flate2::read;
+
+use crate::hash;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    // unusual but handled by srpack and the game
+    Info,
+    // data srpack ignores or works around
+    Warning,
+    // bundle can't be unpacked (completely)
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        })
+    }
+}
+
+pub struct Problem {
+    pub severity: Severity,
+    pub message: String,
+}
+
+struct Report(Vec<Problem>);
+
+impl Report {
+    fn add(&mut self, severity: Severity, message: String) {
+        self.0.push(Problem {
+            severity,
+            message,
+        });
+    }
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    data.get(offset..offset + 4).map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+}
+
+fn u64_at(data: &[u8], offset: usize) -> Option<u64> {
+    data.get(offset..offset + 8).map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+}
+
+// Inflate chunks, reporting chunks that don't decode. Returns the
+// uncompressed data of all chunks that could be read in order.
+fn check_chunks(data: &[u8], uncompressed_size: usize, report: &mut Report) -> Vec<u8> {
+    let mut out = Vec::with_capacity(uncompressed_size);
+    let expected = uncompressed_size.div_ceil(0x10000);
+    let mut offset = 12;
+    let mut chunk = 0;
+    while offset < data.len() {
+        let size = u32_at(data, offset).map(|size| size as usize);
+        // all chunks read and what follows isn't a chunk
+        if size.is_none_or(|size| chunk >= expected && (size > 0x10000 || offset + 4 + size > data.len())) {
+            report.add(Severity::Warning, format!(
+                "{} bytes of trailing garbage at 0x{offset:x}", data.len() - offset));
+            break;
+        }
+        let size = size.unwrap();
+        if size > 0x10000 {
+            report.add(Severity::Error, format!(
+                "chunk {chunk} at 0x{offset:x} has size 0x{size:x} above 0x10000"));
+            break;
+        }
+        if offset + 4 + size > data.len() {
+            report.add(Severity::Error, format!(
+                "chunk {chunk} at 0x{offset:x} is truncated, {} of {size} bytes present",
+                data.len() - offset - 4));
+            break;
+        }
+
+        let compressed = &data[offset + 4..offset + 4 + size];
+        if size == 0x10000 {
+            // stored uncompressed
+            out.extend(compressed);
+        } else {
+            let mut inflated = Vec::with_capacity(0x10000);
+            match read::ZlibDecoder::new(compressed).take(0x10000).read_to_end(&mut inflated) {
+                Ok(_) if inflated.len() == 0x10000 => out.extend(&inflated),
+                Ok(_) => {
+                    report.add(Severity::Warning, format!(
+                        "chunk {chunk} inflates to 0x{:x} bytes instead of 0x10000", inflated.len()));
+                    inflated.resize(0x10000, 0);
+                    out.extend(&inflated);
+                }
+                Err(e) => {
+                    report.add(Severity::Error, format!("chunk {chunk} at 0x{offset:x} does not inflate: {e}"));
+                    break;
+                }
+            }
+        }
+        offset += 4 + size;
+        chunk += 1;
+    }
+
+    if chunk < expected && report.0.iter().all(|p| p.severity != Severity::Error) {
+        report.add(Severity::Error, format!(
+            "{chunk} chunks but uncompressed size 0x{uncompressed_size:x} needs {expected}"));
+    } else if chunk > expected {
+        report.add(Severity::Warning, format!(
+            "{chunk} chunks but uncompressed size 0x{uncompressed_size:x} needs only {expected}"));
+    }
+    out
+}
+
+fn check_resources(data: &[u8], version: u16, report: &mut Report) {
+    let Some(num_files) = u32_at(data, 0) else {
+        report.add(Severity::Error, "no data for index".to_string());
+        return;
+    };
+    let num_files = num_files as usize;
+    let entry_size = match version {
+        6 => 24,
+        5 => 20,
+        _ => 16,
+    };
+    let index_end = num_files.checked_mul(entry_size).and_then(|size| size.checked_add(260));
+    let Some(index) = index_end.and_then(|end| data.get(260..end)) else {
+        report.add(Severity::Error, format!("index of {num_files} resources runs past end of data"));
+        return;
+    };
+
+    let mut offset = 260 + index.len();
+    for (i, entry) in index.chunks_exact(entry_size).enumerate() {
+        let ext_hash = u64_at(entry, 0).unwrap();
+        let name_hash = u64_at(entry, 8).unwrap();
+        let file = match hash::extension_lookup(ext_hash) {
+            Some(ext) => format!("{name_hash:016x}.{ext}"),
+            None => {
+                report.add(Severity::Warning, format!("resource {i} has unknown extension hash {ext_hash:016x}"));
+                format!("{name_hash:016x}.{ext_hash:016x}")
+            }
+        };
+
+        let (Some(data_ext), Some(data_name), Some(num_variants)) =
+            (u64_at(data, offset), u64_at(data, offset + 8), u32_at(data, offset + 16))
+        else {
+            report.add(Severity::Error, format!("data of {file} (resource {i}) runs past end of data"));
+            return;
+        };
+        if data_ext != ext_hash || data_name != name_hash {
+            report.add(Severity::Error, format!(
+                "index entry {i} is {file} but data at 0x{offset:x} is {data_name:016x} with extension hash {data_ext:016x}"));
+            return;
+        }
+
+        let num_variants = num_variants as usize;
+        let mut size = 24usize;
+        let mut first_len = None;
+        for variant in 0..num_variants {
+            let Some(len) = u32_at(data, offset + 24 + variant * 12 + 4) else {
+                report.add(Severity::Error, format!("variants of {file} run past end of data"));
+                return;
+            };
+            first_len.get_or_insert(len);
+            size = size.saturating_add(12 + len as usize);
+        }
+        if offset.saturating_add(size) > data.len() {
+            report.add(Severity::Error, format!(
+                "variant lengths of {file} run 0x{:x} bytes past end of data",
+                offset.saturating_add(size) - data.len()));
+            return;
+        }
+
+        if version == 6 {
+            let index_size = u32_at(entry, 20).unwrap();
+            if first_len.is_some_and(|len| len != index_size) {
+                report.add(Severity::Info, format!(
+                    "index size {index_size} of {file} differs from its first variant ({})", first_len.unwrap()));
+            }
+        }
+        offset += size;
+    }
+
+    if data[offset..].iter().any(|b| *b != 0) {
+        report.add(Severity::Warning, format!(
+            "{} bytes of data after last resource", data.len() - offset));
+    }
+}
+
+fn check_(path: &Path) -> io::Result<Vec<Problem>> {
+    let data = fs::read(path)?;
+    let mut report = Report(Vec::new());
+    if data.len() < 12 {
+        report.add(Severity::Error, format!("{} bytes is too small for bundle header", data.len()));
+        return Ok(report.0);
+    }
+
+    let version = u16::from_le_bytes(data[..2].try_into().unwrap());
+    if !matches!(version, 4..=6) {
+        report.add(Severity::Error, format!("unsupported bundle version {version}"));
+        return Ok(report.0);
+    }
+    let uncompressed_size = u32_at(&data, 4).unwrap() as usize;
+
+    let mut inflated = check_chunks(&data, uncompressed_size, &mut report);
+    if inflated.len() >= uncompressed_size {
+        inflated.truncate(uncompressed_size);
+    }
+    check_resources(&inflated, version, &mut report);
+    Ok(report.0)
+}
+
+// Check structure of bundle. Only fails if the bundle can't be read.
+pub fn check<P: AsRef<Path>>(path: P) -> io::Result<Vec<Problem>> {
+    check_(path.as_ref())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bundle(chunks: &[&[u8]], uncompressed_size: u32) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend(6u16.to_le_bytes());
+        out.extend(0u16.to_le_bytes());
+        out.extend(uncompressed_size.to_le_bytes());
+        out.extend(0u32.to_le_bytes());
+        for chunk in chunks {
+            out.extend((chunk.len() as u32).to_le_bytes());
+            out.extend(*chunk);
+        }
+        out
+    }
+
+    #[test]
+    fn oversized_chunk() {
+        let mut report = Report(Vec::new());
+        let mut data = bundle(&[], 0x10000);
+        data.extend(0x10001u32.to_le_bytes());
+        check_chunks(&data, 0x10000, &mut report);
+        assert_eq!(report.0.len(), 1);
+        assert!(report.0[0].severity == Severity::Error);
+    }
+
+    #[test]
+    fn mismatched_index() {
+        // one stored chunk: index with one lua entry, data naming another resource
+        let mut data = vec![0; 0x10000];
+        data[..4].copy_from_slice(&1u32.to_le_bytes());
+        data[260..268].copy_from_slice(&0xa14e8dfa2cd117e2u64.to_le_bytes());
+        data[268..276].copy_from_slice(&1u64.to_le_bytes());
+        data[284..292].copy_from_slice(&0xa14e8dfa2cd117e2u64.to_le_bytes());
+        data[292..300].copy_from_slice(&2u64.to_le_bytes());
+
+        let mut report = Report(Vec::new());
+        let inflated = check_chunks(&bundle(&[&data], 0x10000), 0x10000, &mut report);
+        check_resources(&inflated, 6, &mut report);
+        assert_eq!(report.0.len(), 1);
+        assert!(report.0[0].message.starts_with("index entry 0"));
+    }
+}
@@ -1,5 +1,7 @@
 use std::hash::Hash;
 use std::hash::Hasher;
+use sha2::Digest;
+use sha2::Sha256;
 
 #[derive(PartialEq, Eq)]
 pub struct MurmurHash64(u64);
@@ -12,6 +14,10 @@ impl MurmurHash64 {
     pub fn from_u64(hash: u64) -> Self {
         Self(hash)
     }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
 }
 
 impl Hash for MurmurHash64 {
@@ -126,3 +132,24 @@ pub(crate) const fn murmur_hash64a(key: &[u8], seed: u64) -> u64 {
     h
 }
 
+// SHA-256 of resource contents, for comparing resources across bundles
+// without keeping copies around.
+pub(crate) fn sha256(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+// Parse hex SHA-256 as written by to_hex.
+pub(crate) fn from_hex(s: &str) -> Option<[u8; 32]> {
+    if s.len() != 64 || !s.is_ascii() {
+        return None;
+    }
+    let mut out = [0; 32];
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(out)
+}
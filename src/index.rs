@@ -1,62 +1,88 @@
+use std::fs;
 use std::fs::File;
 use std::io;
 use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
 use std::path::Path;
+use std::path::PathBuf;
 use byteorder::ReadBytesExt;
+use byteorder::WriteBytesExt;
 use byteorder::LE;
 use flate2::read;
 
 use crate::bundle::BundleFormat;
+use crate::hash;
+use crate::resource;
 
-fn extract_index_(path: &Path) -> io::Result<Vec<(u64, u64, Option<u32>, Option<u32>)>> {
+// (ext_hash, name_hash, flags, size) of every resource in index order, flags
+// only in formats 5 and 6 and size only in format 6
+pub type Index = Vec<(u64, u64, Option<u32>, Option<u32>)>;
+
+// SHA-256 of resources, keyed by (name_hash, ext_hash)
+pub type ContentHashes = Vec<((u64, u64), [u8; 32])>;
+
+fn extract_index_(path: &Path) -> io::Result<(BundleFormat, Index)> {
     let mut index = Vec::new();
-    let mut def_buffer = vec![0; 0x80000];
-    let mut inf_buffer = vec![0; 0x80000];
+    // typical indexes fit in the chunks of the first 0x80000 bytes, larger
+    // ones are read on in further blocks of that size
+    let mut def_buffer = Vec::with_capacity(0x80000);
+    let mut inf_buffer = Vec::with_capacity(0x80000);
     let mut file = File::open(path)?;
-    let read = file.read(&mut def_buffer)?;
-    let mut read_chunks = 8;
+    let mut eof = (&mut file).take(0x80000).read_to_end(&mut def_buffer)? == 0;
+    let mut read_chunks = 1;
     let mut chunks_read = 0;
 
+    if def_buffer.len() < 12 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "bundle header is truncated"));
+    }
     let format = (&def_buffer[..2]).read_u16::<LE>().unwrap();
     let format = match format {
         6 => BundleFormat::Six,
         5 => BundleFormat::Five,
         4 => BundleFormat::Four,
-        i => panic!("unsupported bundle format {i}"),
+        _ => return Err(invalid("unsupported bundle format")),
     };
 
-    let mut data = &def_buffer[12..read];
-    let mut buffer = &mut inf_buffer[..];
-    let mut first = true;
+    let mut offset = 12;
     while chunks_read < read_chunks {
-        if data.len() < 4 {
-            break;
+        let size = def_buffer.get(offset..offset + 4)
+            .map(|size| u32::from_le_bytes(size.try_into().unwrap()) as usize);
+        if size.is_none_or(|size| offset + 4 + size > def_buffer.len()) {
+            if eof {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "index runs past end of bundle"));
+            }
+            def_buffer.drain(..offset);
+            offset = 0;
+            eof = (&mut file).take(0x80000).read_to_end(&mut def_buffer)? == 0;
+            continue;
         }
-        let size = data.read_u32::<LE>()?;
-        if size as usize > data.len() {
-            break;
+        let size = size.unwrap();
+        if size > 0x10000 {
+            return Err(invalid("chunk size above 0x10000"));
         }
-        assert!(size < 0x10000);
-        let copy;
-        (copy, data) = data.split_at(size as usize);
-        let mut e = read::ZlibDecoder::new(copy);
+        let copy = &def_buffer[offset + 4..offset + 4 + size];
+        offset += 4 + size;
 
-        let dest;
-        (dest, buffer) = buffer.split_at_mut(0x10000);
-        e.read_exact(dest)?;
+        let start = inf_buffer.len();
+        if size == 0x10000 {
+            // stored uncompressed
+            inf_buffer.extend(copy);
+        } else {
+            read::ZlibDecoder::new(copy).take(0x10000).read_to_end(&mut inf_buffer)?;
+            inf_buffer.resize(start + 0x10000, 0);
+        }
 
         // check for fastpath
-        if first {
-            let num_files = (&dest[..4]).read_u32::<LE>()?;
+        if chunks_read == 0 {
+            let num_files = (&inf_buffer[..4]).read_u32::<LE>()? as usize;
             let entry_index_size = match format {
                 BundleFormat::Six => 24,
                 BundleFormat::Five => 20,
                 BundleFormat::Four => 16,
             };
             let inf_size = 260 + num_files * entry_index_size;
-            read_chunks = inf_size / 0x10000 + (inf_size % 0x10000 > 0).then(|| 1).unwrap_or(0);
-            assert!(read_chunks <= 8);
-            first = false;
+            read_chunks = inf_size.div_ceil(0x10000);
         }
         chunks_read += 1;
     }
@@ -79,9 +105,438 @@ fn extract_index_(path: &Path) -> io::Result<Vec<(u64, u64, Option<u32>, Option<
         ));
     }
 
-    Ok(index)
+    Ok((format, index))
 }
 
-pub fn extract_index<P: AsRef<Path>>(path: P) -> io::Result<Vec<(u64, u64, Option<u32>, Option<u32>)>> {
+pub fn extract_index<P: AsRef<Path>>(path: P) -> io::Result<Index> {
+    extract_index_(path.as_ref()).map(|(_, index)| index)
+}
+
+// extract_index along with the bundle's format, which can't be told from
+// the entries of an empty index.
+pub fn extract_index_with_format<P: AsRef<Path>>(path: P) -> io::Result<(BundleFormat, Index)> {
     extract_index_(path.as_ref())
 }
+
+// Reads ranges of a bundle's uncompressed data, inflating only the chunks
+// they touch.
+struct Chunks {
+    file: File,
+    format: BundleFormat,
+    uncompressed_size: usize,
+    // (offset in bundle file, compressed size) of every chunk
+    chunks: Vec<(u64, u32)>,
+    cached: Option<usize>,
+    buffer: Vec<u8>,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+impl Chunks {
+    fn open(path: &Path) -> io::Result<Self> {
+        let mut file = File::open(path)?;
+        let format = match file.read_u16::<LE>()? {
+            6 => BundleFormat::Six,
+            5 => BundleFormat::Five,
+            4 => BundleFormat::Four,
+            _ => return Err(invalid("unsupported bundle format")),
+        };
+        let _unknown = file.read_u16::<LE>()?;
+        let uncompressed_size = file.read_u32::<LE>()? as usize;
+        let len = file.metadata()?.len();
+
+        // walk chunk sizes without reading chunk data
+        let mut chunks = Vec::new();
+        let mut offset = file.seek(SeekFrom::Start(12))?;
+        while offset + 4 <= len {
+            let size = file.read_u32::<LE>()?;
+            if size > 0x10000 || offset + 4 + size as u64 > len {
+                return Err(invalid("chunk runs past end of bundle"));
+            }
+            chunks.push((offset + 4, size));
+            offset = file.seek(SeekFrom::Current(size as i64))?;
+        }
+
+        Ok(Self {
+            file,
+            format,
+            uncompressed_size,
+            chunks,
+            cached: None,
+            buffer: Vec::with_capacity(0x10000),
+        })
+    }
+
+    fn chunk(&mut self, index: usize) -> io::Result<&[u8]> {
+        if self.cached != Some(index) {
+            let &(offset, size) = self.chunks.get(index)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "read past last chunk"))?;
+            let mut data = vec![0; size as usize];
+            self.file.seek(SeekFrom::Start(offset))?;
+            self.file.read_exact(&mut data)?;
+
+            self.cached = None;
+            self.buffer.clear();
+            if size == 0x10000 {
+                // stored uncompressed
+                self.buffer.extend(&data);
+            } else {
+                read::ZlibDecoder::new(&data[..]).take(0x10000).read_to_end(&mut self.buffer)?;
+                self.buffer.resize(0x10000, 0);
+            }
+            self.cached = Some(index);
+        }
+        Ok(&self.buffer)
+    }
+
+    fn read_at(&mut self, mut offset: usize, mut out: &mut [u8]) -> io::Result<()> {
+        while !out.is_empty() {
+            let chunk = self.chunk(offset / 0x10000)?;
+            let start = offset % 0x10000;
+            let len = out.len().min(0x10000 - start);
+            let dest;
+            (dest, out) = out.split_at_mut(len);
+            dest.copy_from_slice(&chunk[start..start + len]);
+            offset += len;
+        }
+        Ok(())
+    }
+
+    fn read_u32_at(&mut self, offset: usize) -> io::Result<u32> {
+        let mut data = [0; 4];
+        self.read_at(offset, &mut data)?;
+        Ok(u32::from_le_bytes(data))
+    }
+}
+
+// Walks the resources of a bundle in order. Resource data has no offsets,
+// so each resource's header is read to find the next one.
+struct Resources {
+    chunks: Chunks,
+    entry_size: usize,
+    index: Vec<u8>,
+    next: usize,
+    offset: usize,
+}
+
+// Resource found by Resources::next: position in index, offset of resource
+// data in uncompressed bundle, its size and the lengths of its variants.
+struct Found {
+    position: usize,
+    offset: usize,
+    size: usize,
+    lens: Vec<u32>,
+}
+
+impl Resources {
+    fn open(path: &Path) -> io::Result<Self> {
+        let mut chunks = Chunks::open(path)?;
+        let num_files = chunks.read_u32_at(0)? as usize;
+        let entry_size = match chunks.format {
+            BundleFormat::Six => 24,
+            BundleFormat::Five => 20,
+            BundleFormat::Four => 16,
+        };
+        let mut index = vec![0; num_files * entry_size];
+        chunks.read_at(260, &mut index)?;
+        let offset = 260 + index.len();
+        Ok(Self {
+            chunks,
+            entry_size,
+            index,
+            next: 0,
+            offset,
+        })
+    }
+
+    fn entry(&self, position: usize) -> &[u8] {
+        &self.index[position * self.entry_size..(position + 1) * self.entry_size]
+    }
+
+    fn position(&self, file: (u64, u64)) -> Option<usize> {
+        self.index.chunks_exact(self.entry_size).position(|entry| {
+            entry[..8] == file.1.to_le_bytes() && entry[8..16] == file.0.to_le_bytes()
+        })
+    }
+
+    fn next(&mut self) -> io::Result<Option<Found>> {
+        if self.next * self.entry_size >= self.index.len() {
+            return Ok(None);
+        }
+
+        let mut header = [0; 24];
+        self.chunks.read_at(self.offset, &mut header)?;
+        if self.entry(self.next)[..16] != header[..16] {
+            return Err(invalid("resource data does not match index"));
+        }
+        let num_variants = u32::from_le_bytes(header[16..20].try_into().unwrap()) as usize;
+
+        let mut variants = vec![0; num_variants * 12];
+        self.chunks.read_at(self.offset + 24, &mut variants)?;
+        let lens = variants.chunks_exact(12)
+            .map(|variant| u32::from_le_bytes(variant[4..8].try_into().unwrap()))
+            .collect::<Vec<_>>();
+        let size = 24 + variants.len() + lens.iter().map(|len| *len as usize).sum::<usize>();
+
+        let found = Found {
+            position: self.next,
+            offset: self.offset,
+            size,
+            lens,
+        };
+        self.next += 1;
+        self.offset += size;
+        Ok(Some(found))
+    }
+
+    // Resource in srpack's unpacked layout, same as unpack_bundle (index
+    // prefix + resource data).
+    fn read(&mut self, found: &Found) -> io::Result<Vec<u8>> {
+        let entry = self.entry(found.position);
+        let (flags, file_size) = match self.chunks.format {
+            BundleFormat::Six => (
+                u32::from_le_bytes(entry[16..20].try_into().unwrap()),
+                u32::from_le_bytes(entry[20..24].try_into().unwrap()),
+            ),
+            BundleFormat::Five => (
+                u32::from_le_bytes(entry[16..20].try_into().unwrap()),
+                found.lens.last().copied().unwrap_or(0),
+            ),
+            BundleFormat::Four => (0, found.lens.last().copied().unwrap_or(0)),
+        };
+
+        let mut out = Vec::with_capacity(24 + found.size);
+        out.extend(&entry[..16]);
+        out.write_u32::<LE>(flags)?;
+        out.write_u32::<LE>(file_size)?;
+        out.resize(24 + found.size, 0);
+        self.chunks.read_at(found.offset, &mut out[24..])?;
+        Ok(out)
+    }
+}
+
+fn extract_resource_(path: &Path, file: (u64, u64)) -> io::Result<Option<Vec<u8>>> {
+    let mut resources = Resources::open(path)?;
+    let Some(position) = resources.position(file) else {
+        return Ok(None);
+    };
+    while let Some(found) = resources.next()? {
+        if found.position == position {
+            return resources.read(&found).map(Some);
+        }
+    }
+    unreachable!()
+}
+
+// Read single resource (name_hash, ext_hash) from bundle in srpack's unpacked
+// layout without inflating the whole bundle. Returns None if bundle doesn't
+// hold the resource.
+pub fn extract_resource<P: AsRef<Path>>(path: P, file: (u64, u64)) -> io::Result<Option<Vec<u8>>> {
+    extract_resource_(path.as_ref(), file)
+}
+
+// Collect bundles (16 digit hex names, optionally with a patch_xxx extension)
+// in dir, descending into subdirectories if recursive.
+pub fn find_bundles(dir: &Path, recursive: bool, out: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+        let path = entry.path();
+        if meta.is_dir() && recursive {
+            find_bundles(&path, recursive, out)?;
+        } else if meta.is_file()
+            && let Some(stem) = path.file_stem()
+            && let Some(stem) = stem.to_str()
+            && stem.len() == 16
+            && 9 == path.extension().map(|ext| ext.len()).unwrap_or(9)
+        {
+            out.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn content_hashes_(path: &Path, wanted: &dyn Fn((u64, u64)) -> bool) -> io::Result<ContentHashes> {
+    let mut resources = Resources::open(path)?;
+    let mut out = Vec::new();
+    while let Some(found) = resources.next()? {
+        let entry = resources.entry(found.position);
+        let name_hash = u64::from_le_bytes(entry[8..16].try_into().unwrap());
+        let ext_hash = u64::from_le_bytes(entry[..8].try_into().unwrap());
+        if !wanted((name_hash, ext_hash)) {
+            continue;
+        }
+        let data = resources.read(&found)?;
+        out.push((resource_key(&data), content_hash(&data)));
+    }
+    Ok(out)
+}
+
+// SHA-256 of every resource's data in bundle, keyed by (name_hash, ext_hash).
+pub fn content_hashes<P: AsRef<Path>>(path: P) -> io::Result<ContentHashes> {
+    content_hashes_(path.as_ref(), &|_| true)
+}
+
+// Like content_hashes, but only for resources (name_hash, ext_hash) wanted
+// returns true for. The bundle is still inflated only once.
+pub fn content_hashes_of<P: AsRef<Path>, F: Fn((u64, u64)) -> bool>(path: P, wanted: F) -> io::Result<ContentHashes> {
+    content_hashes_(path.as_ref(), &wanted)
+}
+
+fn resource_key(data: &[u8]) -> (u64, u64) {
+    let ext_hash = u64::from_le_bytes(data[..8].try_into().unwrap());
+    let name_hash = u64::from_le_bytes(data[8..16].try_into().unwrap());
+    (name_hash, ext_hash)
+}
+
+// Hash of resource in srpack's unpacked layout. The index prefix is left out
+// so flags and the unreliable size field don't change the hash.
+pub fn content_hash(data: &[u8]) -> [u8; 32] {
+    hash::sha256(&data[resource::PREFIX_SIZE.min(data.len())..])
+}
+
+pub struct ResourceStats {
+    // (name_hash, ext_hash)
+    pub file: (u64, u64),
+    // bytes of resource data in uncompressed bundle
+    pub size: usize,
+    // share of compressed chunk bytes, by how much of each chunk the resource fills
+    pub compressed: usize,
+}
+
+pub struct BundleStats {
+    pub resources: Vec<ResourceStats>,
+    // uncompressed size of bundle data and sum of its chunk sizes
+    pub uncompressed: usize,
+    pub compressed: usize,
+}
+
+fn bundle_stats_(path: &Path) -> io::Result<BundleStats> {
+    let mut resources = Resources::open(path)?;
+    let mut out = Vec::new();
+    while let Some(found) = resources.next()? {
+        let (start, end) = (found.offset, found.offset + found.size);
+        // sum of chunk size * overlap, divided once at the end and rounded up so
+        // small resources in compressed chunks never show 0
+        let mut weighted = 0u64;
+        for chunk in start / 0x10000..end.div_ceil(0x10000) {
+            let chunk_start = chunk * 0x10000;
+            let overlap = end.min(chunk_start + 0x10000) - start.max(chunk_start);
+            let chunk_size = resources.chunks.chunks.get(chunk).map(|c| c.1 as u64).unwrap_or(0);
+            weighted += chunk_size * overlap as u64;
+        }
+        let compressed = weighted.div_ceil(0x10000) as usize;
+
+        let entry = resources.entry(found.position);
+        out.push(ResourceStats {
+            file: (
+                u64::from_le_bytes(entry[8..16].try_into().unwrap()),
+                u64::from_le_bytes(entry[..8].try_into().unwrap()),
+            ),
+            size: found.size,
+            compressed,
+        });
+    }
+
+    let chunks = &resources.chunks.chunks;
+    Ok(BundleStats {
+        resources: out,
+        uncompressed: resources.chunks.uncompressed_size,
+        compressed: chunks.iter().map(|c| c.1 as usize).sum(),
+    })
+}
+
+// Sizes of resources in bundle, uncompressed and their share of compressed
+// chunks.
+pub fn bundle_stats<P: AsRef<Path>>(path: P) -> io::Result<BundleStats> {
+    bundle_stats_(path.as_ref())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use flate2::write::ZlibEncoder;
+    use flate2::Compression;
+    use super::*;
+
+    // Format 6 bundle holding only an index of num_files entries, with its
+    // chunks stored uncompressed if stored.
+    fn index_bundle(path: &Path, num_files: u32, stored: bool) {
+        let mut data = Vec::new();
+        data.write_u32::<LE>(num_files).unwrap();
+        data.extend([0; 256]);
+        for i in 0..num_files as u64 {
+            data.write_u64::<LE>(i).unwrap();
+            data.write_u64::<LE>(!i).unwrap();
+            data.write_u32::<LE>(i as u32 & 1).unwrap();
+            data.write_u32::<LE>(i as u32).unwrap();
+        }
+
+        let mut bundle = Vec::new();
+        bundle.write_u16::<LE>(6).unwrap();
+        bundle.write_u16::<LE>(0xf000).unwrap();
+        bundle.write_u32::<LE>(data.len() as u32).unwrap();
+        bundle.write_u32::<LE>(0).unwrap();
+        data.resize(data.len().next_multiple_of(0x10000), 0);
+        for chunk in data.chunks(0x10000) {
+            let chunk = if stored {
+                chunk.to_vec()
+            } else {
+                let mut e = ZlibEncoder::new(Vec::new(), Compression::default());
+                e.write_all(chunk).unwrap();
+                e.finish().unwrap()
+            };
+            bundle.write_u32::<LE>(chunk.len() as u32).unwrap();
+            bundle.extend(chunk);
+        }
+        fs::write(path, bundle).unwrap();
+    }
+
+    fn check_index(path: &Path, num_files: u32) {
+        let index = extract_index(path).unwrap();
+        assert_eq!(index.len(), num_files as usize);
+        for (i, entry) in index.into_iter().enumerate() {
+            let i = i as u64;
+            assert_eq!(entry, (i, !i, Some(i as u32 & 1), Some(i as u32)));
+        }
+    }
+
+    #[test]
+    fn index_past_first_block() {
+        // 30000 entries of 24 bytes span 11 chunks, more than the 8 read at first
+        let path = std::env::temp_dir().join(format!("srpack-index-large-{}", std::process::id()));
+        index_bundle(&path, 30000, false);
+        check_index(&path, 30000);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn index_in_stored_chunks() {
+        let path = std::env::temp_dir().join(format!("srpack-index-stored-{}", std::process::id()));
+        index_bundle(&path, 30000, true);
+        check_index(&path, 30000);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn unsupported_format() {
+        let path = std::env::temp_dir().join(format!("srpack-index-format-{}", std::process::id()));
+        fs::write(&path, [7, 0, 0, 0xf0, 0, 0, 0, 0, 0, 0, 0, 0]).unwrap();
+        assert_eq!(extract_index(&path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn content_hash_ignores_index_prefix() {
+        let lua = hash::stingray_hash64(b"lua");
+        let data = resource::wrap(lua, 1, b"print(1)");
+        let mut flagged = data.clone();
+        // flags and size of the index prefix
+        flagged[16..24].copy_from_slice(&[1, 0, 0, 0, 0xff, 0xff, 0, 0]);
+        assert_eq!(content_hash(&flagged), content_hash(&data));
+        assert_eq!(content_hash(&data), hash::sha256(&data[resource::PREFIX_SIZE..]));
+        assert_ne!(content_hash(&resource::wrap(lua, 1, b"print(2)")), content_hash(&data));
+    }
+}
@@ -1,6 +1,7 @@
 #![allow(dead_code)]
 use std::collections::HashMap;
 use std::collections::BTreeMap;
+use std::collections::HashSet;
 use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering;
 use std::sync::mpsc;
@@ -12,6 +13,8 @@ use std::io::BufReader;
 use std::io::Write;
 use std::mem;
 use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
 
 #[macro_use]
 mod cli;
@@ -20,7 +23,17 @@ use cli::CommandBuilder;
 mod hash;
 use hash::MurmurHash64;
 mod bundle;
+mod cache;
+mod convert;
+mod database;
+mod diff;
+mod fsck;
 mod index;
+mod json;
+mod output;
+mod patch;
+mod resource;
+mod select;
 
 const PADDING: &str = "                                ";
 
@@ -35,25 +48,93 @@ const DICTIONARY: Switch = Switch::short("d", "dictionary")
 const SKIP_HASH: Switch = Switch::new("skip-hash")
     .with_desc("Don't print files that have an unknown name hash.");
 
+const CONVERT: Switch = Switch::new("convert")
+    .with_params(&["KINDS"])
+    .with_desc("Convert resources to plain files (lua, textures, strings, strings-csv, media, all).");
+
+const CONFLICT: Switch = Switch::short("c", "conflict")
+    .with_params(&["POLICY"])
+    .with_desc("Resolve duplicates with last, first, error or newer.");
+
+const EXT: Switch = Switch::new("ext")
+    .with_params(&["EXTS"])
+    .with_desc("Select resources with extensions (lua,package).");
+
+const NAME: Switch = Switch::new("name")
+    .with_params(&["GLOBS"])
+    .with_desc("Select resources with names matching globs ('scripts/**').");
+
+const NAME_HASH: Switch = Switch::new("hash")
+    .with_params(&["HASHES"])
+    .with_desc("Select resources with name hashes (0x...).");
+
+const SELECT_FLAGS: Switch = Switch::new("flags")
+    .with_params(&["FLAGS"])
+    .with_desc("Select resources with flags (deleted, live or value).");
+
+const FORMAT: Switch = Switch::new("format")
+    .with_params(&["FORMAT"])
+    .with_desc("Output as table, json, ndjson or csv.");
+
+const RECURSIVE: Switch = Switch::short("r", "recursive")
+    .with_desc("Include bundles in subdirectories.");
+
+const DUPLICATES: Switch = Switch::new("duplicates")
+    .with_desc("List resources found in more than one bundle instead.");
+
+const CONTENT_HASH: Switch = Switch::new("content-hash")
+    .with_desc("Hash resource data with SHA-256 (inflates whole bundles).");
+
+const CACHE: Switch = Switch::new("cache")
+    .with_params(&["FILE"])
+    .with_desc("Keep bundle indexes in FILE and only re-index changed bundles.");
+
+const WATCH: Switch = Switch::new("watch")
+    .with_desc("Repack whenever files in the directory change.");
+
+const SALVAGE: Switch = Switch::new("salvage")
+    .with_desc("Recover what is readable from a damaged bundle and list lost resources.");
+
 const MERGE: CommandBuilder = command![
         NUM_THREADS,
+        CONFLICT,
+        DICTIONARY,
     ].with_name("merge")
-    .with_params(&["slow"]);
+    .with_short_desc("Merge bundles into one bundle.")
+    .with_desc("Merge bundles into one bundle. Resources in more than one bundle are \
+        resolved by the conflict policy (last bundle wins by default).")
+    .with_params(&["out", "bundle"])
+    .with_variadic();
 
 const UNPACK: CommandBuilder = command![
         NUM_THREADS,
+        CONVERT,
+        DICTIONARY,
+        CONTENT_HASH,
+        SALVAGE,
+        EXT,
+        NAME,
+        NAME_HASH,
+        SELECT_FLAGS,
     ].with_name("unpack")
     .with_short_desc("Unpack bundle into directory.")
     .with_params(&["bundle", "dir"]);
 
 const REPACK: CommandBuilder = command![
         NUM_THREADS,
+        WATCH,
     ].with_name("repack")
     .with_short_desc("Pack files in directory into bundle.")
     .with_params(&["dir", "bundle"]);
 
 const INDEX: CommandBuilder = command![
         DICTIONARY,
+        FORMAT,
+        CONTENT_HASH,
+        EXT,
+        NAME,
+        NAME_HASH,
+        SELECT_FLAGS,
     ].with_name("index")
     .with_short_desc("List index of bundle.")
     .with_params(&["bundle"]);
@@ -62,9 +143,150 @@ const SCAN: CommandBuilder = command![
         NUM_THREADS,
         DICTIONARY,
         SKIP_HASH,
+        FORMAT,
+        CONTENT_HASH,
+        EXT,
+        NAME,
+        NAME_HASH,
+        SELECT_FLAGS,
+        RECURSIVE,
+        DUPLICATES,
+        CACHE,
     ].with_name("scan")
-    .with_short_desc("Index all bundles in directory.")
-    .with_params(&["directory"]);
+    .with_short_desc("Index all bundles in directories.")
+    .with_params(&["directory"])
+    .with_variadic();
+
+const FIND: CommandBuilder = command![
+        NUM_THREADS,
+        DICTIONARY,
+        RECURSIVE,
+    ].with_name("find")
+    .with_short_desc("Find bundles containing resource.")
+    .with_desc("Find bundles in directory containing resource, given as path or hex \
+        name hash with extension, or as name hash alone to match any extension.")
+    .with_params(&["directory", "name.ext"]);
+
+const TOP: Switch = Switch::new("top")
+    .with_params(&["N"])
+    .with_desc("Number of largest resources to list (default 10).");
+
+const STATS: CommandBuilder = command![
+        NUM_THREADS,
+        DICTIONARY,
+        RECURSIVE,
+        TOP,
+    ].with_name("stats")
+    .with_short_desc("Show size and compression statistics.")
+    .with_desc("Show resource counts, uncompressed and compressed sizes by extension and \
+        bundle for bundle or directory of bundles, and the largest resources. Compressed \
+        sizes of resources are their share of the chunks they are stored in.")
+    .with_params(&["path"]);
+
+const FSCK: CommandBuilder = command![]
+    .with_name("fsck")
+    .with_short_desc("Check bundles for structural problems.")
+    .with_desc("Check bundles for structural problems. Exits with 2 if any bundle has \
+        errors, 1 if any has warnings.")
+    .with_params(&["bundle"])
+    .with_variadic();
+
+const DATABASE: CommandBuilder = command![
+        DICTIONARY,
+    ].with_name("database")
+    .with_short_desc("List bundle_database.data.")
+    .with_params(&["database"]);
+
+const CHAIN: CommandBuilder = command![
+        NUM_THREADS,
+        DICTIONARY,
+    ].with_name("chain")
+    .with_short_desc("Resolve bundle with its patch bundles.")
+    .with_desc("List resources of bundle after applying its patch_xxx bundles, \
+        or unpack them into directory.")
+    .with_params(&["bundle", "dir"]);
+
+const BYTES: Switch = Switch::new("bytes")
+    .with_desc("Summarize byte differences of modified resources.");
+
+const DIFF: CommandBuilder = command![
+        NUM_THREADS,
+        DICTIONARY,
+        BYTES,
+    ].with_name("diff")
+    .with_short_desc("List resource changes between bundles.")
+    .with_params(&["a", "b"]);
+
+const DELETED: Switch = Switch::new("deleted")
+    .with_params(&["FLAG"])
+    .with_desc("Add removed resources as deleted entries (1 or 2).");
+
+const DELTA: CommandBuilder = command![
+        NUM_THREADS,
+        DELETED,
+    ].with_name("delta")
+    .with_short_desc("Pack only changed resources into bundle.")
+    .with_desc("Pack resources of edited bundle or unpacked directory that are new \
+        or differ from base bundle into bundle.")
+    .with_params(&["base", "edited", "out"]);
+
+const DELETED_FLAGS: Switch = Switch::new("deleted-flags")
+    .with_params(&["FLAGS"])
+    .with_desc("Deleted flags to set, 1 (default) or 2.");
+
+const TOMBSTONE: CommandBuilder = command![
+        NUM_THREADS,
+        DELETED_FLAGS,
+    ].with_name("tombstone")
+    .with_short_desc("Mark resources deleted in bundle.")
+    .with_desc("Mark resources (hex name hash or path with extension) deleted in bundle, \
+        adding deleted entries for resources the bundle doesn't hold. In an unpacked \
+        directory an empty file <name>.<ext>.deleted does the same on repack.")
+    .with_params(&["bundle", "name.ext"])
+    .with_variadic();
+
+const SET: CommandBuilder = command![
+        NUM_THREADS,
+    ].with_name("set")
+    .with_short_desc("Add or replace resource in bundle.")
+    .with_desc("Add or replace resource in bundle with file, either an unpacked resource, \
+        a converted file (.lua, .dds, .strings.json, ...) or a raw payload.")
+    .with_params(&["bundle", "name.ext", "file"]);
+
+const RM: CommandBuilder = command![
+        NUM_THREADS,
+    ].with_name("rm")
+    .with_short_desc("Remove resources from bundle.")
+    .with_params(&["bundle", "name.ext"])
+    .with_variadic();
+
+const RAW: Switch = Switch::new("raw")
+    .with_desc("Write payload of first variant without resource header.");
+
+const EXTRACT: CommandBuilder = command![
+        DICTIONARY,
+        RAW,
+    ].with_name("extract")
+    .with_short_desc("Extract single resource from bundle.")
+    .with_desc("Extract resource (hex name hash or path with extension) from bundle into \
+        out, by default the resource's file name in the current directory.")
+    .with_params(&["bundle", "name.ext", "out"]);
+
+const PREFIX: Switch = Switch::new("prefix")
+    .with_desc("Keep srpack's 24 byte index prefix.");
+
+const LANGUAGE: Switch = Switch::short("l", "language")
+    .with_params(&["ID"])
+    .with_desc("Write only payload of localization variant (decimal or 0x hex).");
+
+const CAT: CommandBuilder = command![
+        PREFIX,
+        LANGUAGE,
+    ].with_name("cat")
+    .with_short_desc("Write resource to stdout.")
+    .with_desc("Write resource data (hex name hash or path with extension) from bundle to \
+        stdout, or the payload of a single variant with --language.")
+    .with_params(&["bundle", "name.ext"]);
 
 const HASH: CommandBuilder = command![]
     .with_name("hash")
@@ -76,13 +298,32 @@ const TEST: CommandBuilder = command![]
     .with_short_desc("Test unpack and repack on bundle.")
     .with_params(&["bundle"]);
 
+fn deleted_flags(flags: &std::ffi::OsStr) -> u32 {
+    flags.to_str()
+        .and_then(|flags| flags.parse::<u32>().ok())
+        .filter(|flags| resource::is_deleted(*flags))
+        .expect("deleted flags must be 1 or 2")
+}
+
 fn main() {
     let app = app![
-        //MERGE,
+        MERGE,
         UNPACK,
         REPACK,
         INDEX,
         SCAN,
+        FIND,
+        STATS,
+        FSCK,
+        DATABASE,
+        CHAIN,
+        DIFF,
+        DELTA,
+        TOMBSTONE,
+        SET,
+        RM,
+        EXTRACT,
+        CAT,
         HASH,
         TEST,
     ];
@@ -106,27 +347,159 @@ fn main() {
                     let line = line.unwrap();
                     lookup.insert(MurmurHash64::new(&line), line);
                 }
-                lookup
+                Arc::new(lookup)
             });
 
+        let convert = app.switch_params(CONVERT)
+            .and_then(|mut params| params.next())
+            .map(|kinds| convert::Convert::parse(&kinds.to_string_lossy()).unwrap())
+            .unwrap_or_default();
+
+        let mut select = select::Selector::default();
+        if let Some(exts) = app.switch_params(EXT).and_then(|mut params| params.next()) {
+            select.ext(&exts.to_string_lossy()).unwrap();
+        }
+        if let Some(globs) = app.switch_params(NAME).and_then(|mut params| params.next()) {
+            select.name(&globs.to_string_lossy());
+        }
+        if let Some(hashes) = app.switch_params(NAME_HASH).and_then(|mut params| params.next()) {
+            select.hash(&hashes.to_string_lossy()).unwrap();
+        }
+        if let Some(flags) = app.switch_params(SELECT_FLAGS).and_then(|mut params| params.next()) {
+            select.flags(&flags.to_string_lossy()).unwrap();
+        }
+
+        let format = app.switch_params(FORMAT)
+            .and_then(|mut params| params.next())
+            .map(|format| output::Format::parse(&format.to_string_lossy()).expect("unknown output format"))
+            .unwrap_or(output::Format::Table);
+
         let settings = bundle::PackSettings {
             num_threads,
+            convert,
+            dictionary: dictionary.clone(),
+            select: select.clone(),
+            content_hash: app.switch_active(&CONTENT_HASH),
         };
 
         let mut params = app.params();
         if app.subcmd(&MERGE) {
+            let out = params.next().expect("failed to parse parameter out");
+            let conflict = app.switch_params(CONFLICT)
+                .and_then(|mut params| params.next())
+                .map(|policy| {
+                    let policy = policy.to_string_lossy();
+                    bundle::Conflict::parse(&policy).unwrap_or_else(|| {
+                        eprintln!("unknown conflict policy \"{policy}\", expected last, first, error or newer");
+                        std::process::exit(1);
+                    })
+                })
+                .unwrap_or(bundle::Conflict::LastWins);
+
+            let mut merge = bundle::Merge::new(&settings);
+            merge.conflict = conflict;
+            let mut count = 0;
+            for bundle in params {
+                let result = merge.unpack_from(bundle);
+                // expected outcome of --conflict error, not a bug
+                if let Err(e) = &result
+                    && e.kind() == io::ErrorKind::AlreadyExists
+                {
+                    eprintln!("conflict: {e}");
+                    std::process::exit(1);
+                }
+                result.unwrap();
+                count += 1;
+            }
+            assert!(count > 0, "no bundles to merge");
+
+            for o in merge.overrides.iter() {
+                let (name_hash, ext_hash) = o.file;
+                let ext = hash::extension_lookup(ext_hash).unwrap_or("?");
+                let kept = merge.bundles[o.kept].file_name().unwrap().to_string_lossy();
+                let dropped = merge.bundles[o.dropped].file_name().unwrap().to_string_lossy();
+                if let Some(dict) = &dictionary
+                    && let Some(name) = dict.get(&MurmurHash64::from_u64(name_hash))
+                {
+                    println!("{name}.{ext}   {kept} over {dropped}");
+                } else {
+                    println!("{name_hash:016x}.{ext}   {kept} over {dropped}");
+                }
+            }
+            eprintln!("merged {count} bundles, {} resources, {} overridden",
+                merge.files.len(), merge.overrides.len());
+            merge.repack_to(out).unwrap();
         } else if app.subcmd(&UNPACK) {
             let bundle = params.next().expect("failed to parse parameter bundle");
             let dir = params.next().expect("failed to parse parameter directory");
-            bundle::unpack_bundle_to_dir(bundle, dir, &settings).unwrap();
+            if app.switch_active(&SALVAGE) {
+                let salvage = bundle::salvage_bundle_to_dir(bundle, dir, &settings).unwrap();
+                for (name_hash, ext_hash) in salvage.lost.iter() {
+                    let ext = hash::extension_lookup(*ext_hash).unwrap_or("?");
+                    if let Some(dict) = &dictionary
+                        && let Some(name) = dict.get(&MurmurHash64::from_u64(*name_hash))
+                    {
+                        println!("lost {name}.{ext}");
+                    } else {
+                        println!("lost {name_hash:016x}.{ext}");
+                    }
+                }
+                eprintln!("{} of {} chunks unreadable, recovered {} resources, lost {}",
+                    salvage.bad_chunks, salvage.chunks, salvage.recovered, salvage.lost.len());
+            } else {
+                bundle::unpack_bundle_to_dir(bundle, dir, &settings).unwrap();
+            }
         } else if app.subcmd(&REPACK) {
             let dir = params.next().expect("failed to parse parameter directory");
             let bundle = params.next().expect("failed to parse parameter bundle");
-            bundle::pack_dir_to_bundle(dir, bundle, &settings).unwrap();
+            if app.switch_active(&WATCH) {
+                // keep watching when a pack fails, e.g. on a half-saved file
+                bundle::watch_dir_to_bundle(dir, bundle, &settings, Duration::from_millis(500), |result| {
+                    match result {
+                        Ok(()) => eprintln!("packed {}", Path::new(bundle).display()),
+                        Err(e) => eprintln!("failed to pack {}: {e}", Path::new(bundle).display()),
+                    }
+                    true
+                }).unwrap();
+            } else {
+                bundle::pack_dir_to_bundle(dir, bundle, &settings).unwrap();
+            }
         } else if app.subcmd(&INDEX) {
             let bundle = params.next().expect("failed to parse parameter bundle");
-            let index = index::extract_index(bundle).unwrap();
-            if !index.is_empty() {
+            let (bundle_format, mut index) = index::extract_index_with_format(bundle).unwrap();
+            index.retain(|(ext_hash, name_hash, flags, _)| {
+                select.matches(*ext_hash, *name_hash, *flags, dictionary.as_deref())
+            });
+            let content_hashes = settings.content_hash.then(|| {
+                index::content_hashes(bundle).unwrap()
+                    .into_iter()
+                    .map(|(file, sha256)| (file, hash::to_hex(&sha256)))
+                    .collect::<HashMap<_, _>>()
+            });
+            if format != output::Format::Table {
+                let name = Path::new(bundle).file_name().unwrap().to_string_lossy();
+                let mut lines = Vec::new();
+                for (ext_hash, name_hash, flags, size) in index.iter() {
+                    output::write_entry(format, &mut lines, &output::Entry {
+                        bundle: &name,
+                        format: bundle_format,
+                        ext_hash: *ext_hash,
+                        name_hash: *name_hash,
+                        flags: *flags,
+                        size: *size,
+                        ext: hash::extension_lookup(*ext_hash),
+                        name: dictionary.as_ref()
+                            .and_then(|dict| dict.get(&MurmurHash64::from_u64(*name_hash)))
+                            .map(|name| &name[..]),
+                        sha256: content_hashes.as_ref()
+                            .and_then(|hashes| hashes.get(&(*name_hash, *ext_hash)))
+                            .map(|sha256| &sha256[..]),
+                    });
+                }
+                let mut writer = output::Writer::new(format, io::stdout().lock()).unwrap();
+                writer.write_lines(&lines).unwrap();
+                writer.finish().unwrap();
+            } else if !index.is_empty() {
                 let mut longest = 0;
                 for (ext_hash, ..) in index.iter() {
                     if let Some(ext) = hash::extension_lookup(*ext_hash) {
@@ -136,7 +509,8 @@ fn main() {
 
                 let padding = longest.saturating_sub(16);
                 println!();
-                println!(" flags        size {}    extension            name", &PADDING[..padding]);
+                let sha256 = if content_hashes.is_some() { format!("{:^66}", "sha256") } else { String::new() };
+                println!(" flags        size {}    extension        {sha256}    name", &PADDING[..padding]);
                 for (ext_hash, name_hash, flags, size) in index.iter() {
                     if let Some(flags) = flags {
                         print!("   {flags}  ");
@@ -159,6 +533,10 @@ fn main() {
                             &PADDING[..padding]);
                     };
 
+                    if let Some(hashes) = &content_hashes {
+                        print!(" {:64} ", hashes.get(&(*name_hash, *ext_hash)).map(|h| &h[..]).unwrap_or(""));
+                    }
+
                     if let Some(dict) = &dictionary
                         && let Some(name) = dict.get(&MurmurHash64::from_u64(*name_hash))
                     {
@@ -171,25 +549,26 @@ fn main() {
                 }
             }
         } else if app.subcmd(&SCAN) {
-            let dir = params.next().expect("failed to parse parameter bundle");
             let skip_hashes = app.switch_active(&SKIP_HASH);
-            let mut files = fs::read_dir(dir).unwrap()
-                .filter_map(|entry| {
-                    if let Ok(entry) = entry
-                        && let Ok(meta) = entry.metadata()
-                        && meta.is_file()
-                        && let path = entry.path()
-                        && let Some(stem) = path.file_stem()
-                        && let Some(stem) = stem.to_str()
-                        && stem.len() == 16
-                        && 9 == path.extension().map(|ext| ext.len()).unwrap_or(9)
-                    {
-                        Some(path)
-                    } else {
-                        None
-                    }
-                }).collect::<Vec<_>>();
+            let recursive = app.switch_active(&RECURSIVE);
+            let duplicates = app.switch_active(&DUPLICATES);
+            assert!(!duplicates || app.switch_params(FORMAT).is_none(), "--format is not supported with --duplicates");
+            let dirs = params.map(Path::new).collect::<Vec<_>>();
+            assert!(!dirs.is_empty(), "no directory to scan");
+            let mut files = Vec::new();
+            for dir in dirs.iter() {
+                index::find_bundles(dir, recursive, &mut files).unwrap();
+            }
             files.sort_unstable();
+            let cache_file = app.switch_params(CACHE).and_then(|mut params| params.next());
+            let cache = cache_file.map(cache::IndexCache::read);
+            // bundles are named relative to the directory when scanning only one
+            let labels = files.iter()
+                .map(|path| match &dirs[..] {
+                    [dir] => path.strip_prefix(dir).unwrap().to_string_lossy(),
+                    _ => path.to_string_lossy(),
+                })
+                .collect::<Vec<_>>();
 
             let (tx, rx) = mpsc::channel();
             let job = AtomicUsize::new(0);
@@ -201,19 +580,62 @@ fn main() {
                         let mut buffer = Vec::with_capacity(0x10000);
                         let mut i = job.fetch_add(1, Ordering::SeqCst);
                         while let Some(path) = files.get(i) {
-                            let bundle = path.file_name().unwrap().to_str().unwrap();
-                            let index = index::extract_index(&path).unwrap();
-                            for (ext_hash, name_hash, ..) in index.iter() {
+                            let bundle = &labels[i][..];
+                            let (bundle_format, mut index) = match &cache {
+                                Some(cache) => cache.index(path).unwrap(),
+                                None => index::extract_index_with_format(path).unwrap(),
+                            };
+                            index.retain(|(ext_hash, name_hash, flags, _)| {
+                                select.matches(*ext_hash, *name_hash, *flags, dictionary.as_deref())
+                            });
+                            let content_hashes = settings.content_hash.then(|| {
+                                index::content_hashes(path).unwrap()
+                                    .into_iter()
+                                    .collect::<HashMap<_, _>>()
+                            });
+                            // with hashes if already computed, for comparing duplicates
+                            let keys = index.iter()
+                                .filter(|_| duplicates)
+                                .map(|(ext_hash, name_hash, ..)| {
+                                    let key = (*name_hash, *ext_hash);
+                                    (key, content_hashes.as_ref().and_then(|hashes| hashes.get(&key)).copied())
+                                })
+                                .collect::<Vec<_>>();
+                            for (ext_hash, name_hash, flags, size) in index.iter().filter(|_| !duplicates) {
                                 let ext = hash::extension_lookup(*ext_hash).unwrap();
-                                if let Some(dict) = &dictionary
-                                    && let Some(name) = dict.get(&MurmurHash64::from_u64(*name_hash))
-                                {
-                                    writeln!(buffer, "{bundle:<26}   {name}.{ext}").unwrap();
-                                } else if !skip_hashes {
-                                    writeln!(buffer, "{bundle:<26}   {name_hash:016x}.{ext}").unwrap();
+                                let name = dictionary.as_ref()
+                                    .and_then(|dict| dict.get(&MurmurHash64::from_u64(*name_hash)));
+                                let sha256 = content_hashes.as_ref()
+                                    .and_then(|hashes| hashes.get(&(*name_hash, *ext_hash)))
+                                    .map(|sha256| hash::to_hex(sha256));
+                                let sha256 = sha256.as_deref();
+                                if format != output::Format::Table {
+                                    if name.is_some() || !skip_hashes {
+                                        output::write_entry(format, &mut buffer, &output::Entry {
+                                            bundle,
+                                            format: bundle_format,
+                                            ext_hash: *ext_hash,
+                                            name_hash: *name_hash,
+                                            flags: *flags,
+                                            size: *size,
+                                            ext: Some(ext),
+                                            name: name.map(|name| &name[..]),
+                                            sha256,
+                                        });
+                                    }
+                                } else if name.is_some() || !skip_hashes {
+                                    write!(buffer, "{bundle:<26}   ").unwrap();
+                                    if let Some(sha256) = sha256 {
+                                        write!(buffer, "{sha256}   ").unwrap();
+                                    }
+                                    if let Some(name) = name {
+                                        writeln!(buffer, "{name}.{ext}").unwrap();
+                                    } else {
+                                        writeln!(buffer, "{name_hash:016x}.{ext}").unwrap();
+                                    }
                                 }
                             }
-                            tx.send((i, mem::take(&mut buffer))).unwrap();
+                            tx.send((i, mem::take(&mut buffer), keys)).unwrap();
                             i = job.fetch_add(1, Ordering::SeqCst);
                         }
                     });
@@ -221,12 +643,25 @@ fn main() {
                 }
                 drop(tx);
 
+                let mut writer = (!duplicates).then(|| output::Writer::new(format, io::stdout().lock()).unwrap());
                 let mut count = 0;
                 let mut list = BTreeMap::new();
-                for (mut i, mut buffer) in rx.iter() {
+                let mut found = HashMap::<(u64, u64), Vec<usize>>::new();
+                // content hash of each (resource, bundle) in duplicates
+                let mut contents = HashMap::new();
+                for (mut i, mut buffer, keys) in rx.iter() {
+                    for (key, sha256) in keys {
+                        found.entry(key).or_default().push(i);
+                        if let Some(sha256) = sha256 {
+                            contents.insert((key, i), sha256);
+                        }
+                    }
+                    let Some(writer) = &mut writer else {
+                        continue;
+                    };
                     if i == count {
                         while i == count {
-                            io::stdout().write_all(&buffer).unwrap();
+                            writer.write_lines(&buffer).unwrap();
                             count += 1;
                             if let Some(entry) = list.remove(&count) {
                                 i = count;
@@ -237,7 +672,510 @@ fn main() {
                         list.insert(i, buffer);
                     }
                 }
+                if let Some(writer) = writer {
+                    writer.finish().unwrap();
+                    return;
+                }
+
+                let mut found = found.into_iter()
+                    .filter(|(_, bundles)| bundles.len() > 1)
+                    .collect::<Vec<_>>();
+                found.sort_unstable_by_key(|((name_hash, ext_hash), _)| (*ext_hash, *name_hash));
+
+                // hash duplicated resources not hashed yet, inflating each bundle once
+                let mut wanted = BTreeMap::<usize, HashSet<(u64, u64)>>::new();
+                for (key, bundles) in found.iter() {
+                    for i in bundles.iter().filter(|i| !contents.contains_key(&(*key, **i))) {
+                        wanted.entry(*i).or_default().insert(*key);
+                    }
+                }
+                let wanted = wanted.into_iter().collect::<Vec<_>>();
+                let job = AtomicUsize::new(0);
+                thread::scope(|s| {
+                    let (tx, rx) = mpsc::channel();
+                    for _ in 0..num_threads {
+                        let tx = tx.clone();
+                        s.spawn(|| {
+                            let tx = tx;
+                            let mut j = job.fetch_add(1, Ordering::SeqCst);
+                            while let Some((i, keys)) = wanted.get(j) {
+                                let hashes = index::content_hashes_of(&files[*i], |key| keys.contains(&key)).unwrap();
+                                tx.send((*i, hashes)).unwrap();
+                                j = job.fetch_add(1, Ordering::SeqCst);
+                            }
+                        });
+                    }
+                    drop(tx);
+                    for (i, hashes) in rx.iter() {
+                        for (key, sha256) in hashes {
+                            contents.insert((key, i), sha256);
+                        }
+                    }
+                });
+
+                let mut stdout = io::stdout().lock();
+                for ((name_hash, ext_hash), mut bundles) in found {
+                    bundles.sort_unstable();
+                    let first = contents.get(&((name_hash, ext_hash), bundles[0]));
+                    let identical = bundles.iter()
+                        .all(|i| contents.get(&((name_hash, ext_hash), *i)) == first);
+
+                    let ext = hash::extension_lookup(ext_hash).unwrap_or("?");
+                    if let Some(dict) = &dictionary
+                        && let Some(name) = dict.get(&MurmurHash64::from_u64(name_hash))
+                    {
+                        write!(stdout, "{name}.{ext}").unwrap();
+                    } else {
+                        write!(stdout, "{name_hash:016x}.{ext}").unwrap();
+                    }
+                    writeln!(stdout, "   {}", if identical { "identical" } else { "different" }).unwrap();
+                    for i in bundles {
+                        writeln!(stdout, "    {}", labels[i]).unwrap();
+                    }
+                }
+            });
+            if let (Some(cache), Some(cache_file)) = (cache, cache_file) {
+                cache.write(cache_file).unwrap();
+            }
+        } else if app.subcmd(&FIND) {
+            let dir = params.next().expect("failed to parse parameter directory");
+            let query = params.next().expect("failed to parse parameter name.ext").to_string_lossy();
+            let (name_hash, ext_hash) = match resource::parse_file_name(&query) {
+                Some((name_hash, ext_hash)) => (name_hash, Some(ext_hash)),
+                None => {
+                    let hex = query.strip_prefix("0x").unwrap_or(&query);
+                    let name_hash = u64::from_str_radix(hex, 16)
+                        .unwrap_or_else(|_| hash::stingray_hash64(query.as_bytes()));
+                    (name_hash, None)
+                }
+            };
+
+            let mut files = Vec::new();
+            index::find_bundles(Path::new(dir), app.switch_active(&RECURSIVE), &mut files).unwrap();
+            files.sort_unstable();
+
+            let (tx, rx) = mpsc::channel();
+            let job = AtomicUsize::new(0);
+            thread::scope(|s| {
+                for _ in 0..num_threads {
+                    let tx = tx.clone();
+                    s.spawn(|| {
+                        let tx = tx;
+                        let mut i = job.fetch_add(1, Ordering::SeqCst);
+                        while let Some(path) = files.get(i) {
+                            let found = index::extract_index(path).unwrap()
+                                .into_iter()
+                                .filter(|entry| entry.1 == name_hash && ext_hash.is_none_or(|ext| entry.0 == ext))
+                                .collect::<Vec<_>>();
+                            tx.send((i, found)).unwrap();
+                            i = job.fetch_add(1, Ordering::SeqCst);
+                        }
+                    });
+                }
+                drop(tx);
+
+                let mut found = rx.iter().filter(|(_, found)| !found.is_empty()).collect::<Vec<_>>();
+                found.sort_unstable_by_key(|(i, _)| *i);
+                if found.is_empty() {
+                    eprintln!("not found");
+                }
+                for (i, entries) in found {
+                    let bundle = files[i].strip_prefix(dir).unwrap().to_string_lossy();
+                    for (ext_hash, name_hash, flags, size) in entries {
+                        let flags = flags.map(|flags| flags.to_string()).unwrap_or("N/A".to_string());
+                        let size = size.map(|size| size.to_string()).unwrap_or("N/A".to_string());
+                        let ext = hash::extension_lookup(ext_hash).unwrap();
+                        if let Some(dict) = &dictionary
+                            && let Some(name) = dict.get(&MurmurHash64::from_u64(name_hash))
+                        {
+                            println!("{bundle:<26}   {flags:>3}   {size:>10}   {name}.{ext}");
+                        } else {
+                            println!("{bundle:<26}   {flags:>3}   {size:>10}   {name_hash:016x}.{ext}");
+                        }
+                    }
+                }
             });
+        } else if app.subcmd(&STATS) {
+            let path = Path::new(params.next().expect("failed to parse parameter path"));
+            let top = app.switch_params(TOP)
+                .and_then(|mut params| params.next())
+                .map(|n| n.to_str().and_then(|n| n.parse::<usize>().ok()).expect("failed to parse N"))
+                .unwrap_or(10);
+            let mut files = Vec::new();
+            if path.is_dir() {
+                index::find_bundles(path, app.switch_active(&RECURSIVE), &mut files).unwrap();
+                files.sort_unstable();
+            } else {
+                files.push(path.to_path_buf());
+            }
+            let labels = files.iter()
+                .map(|file| match file.strip_prefix(path) {
+                    Ok(label) if !label.as_os_str().is_empty() => label.to_string_lossy(),
+                    _ => file.file_name().unwrap().to_string_lossy(),
+                })
+                .collect::<Vec<_>>();
+
+            let (tx, rx) = mpsc::channel();
+            let job = AtomicUsize::new(0);
+            let mut stats = thread::scope(|s| {
+                for _ in 0..num_threads {
+                    let tx = tx.clone();
+                    s.spawn(|| {
+                        let tx = tx;
+                        let mut i = job.fetch_add(1, Ordering::SeqCst);
+                        while let Some(path) = files.get(i) {
+                            tx.send((i, index::bundle_stats(path).unwrap())).unwrap();
+                            i = job.fetch_add(1, Ordering::SeqCst);
+                        }
+                    });
+                }
+                drop(tx);
+                rx.iter().collect::<Vec<_>>()
+            });
+            stats.sort_unstable_by_key(|(i, _)| *i);
+
+            // (count, uncompressed, compressed)
+            fn row(label: &str, (count, size, compressed): (usize, usize, usize)) {
+                let ratio = if size > 0 { compressed as f64 * 100.0 / size as f64 } else { 0.0 };
+                println!(" {label:<26} {count:>8} {size:>14} {compressed:>14} {ratio:>7.1}%");
+            }
+            fn header(label: &str) {
+                println!();
+                println!(" {label:<26} {:>8} {:>14} {:>14} {:>8}", "count", "uncompressed", "compressed", "ratio");
+            }
+
+            let mut by_ext = BTreeMap::<&str, (usize, usize, usize)>::new();
+            let mut total = (0, 0, 0);
+            for (_, bundle) in stats.iter() {
+                for resource in bundle.resources.iter() {
+                    let ext = hash::extension_lookup(resource.file.1).unwrap_or("?");
+                    let entry = by_ext.entry(ext).or_default();
+                    entry.0 += 1;
+                    entry.1 += resource.size;
+                    entry.2 += resource.compressed;
+                }
+                total.0 += bundle.resources.len();
+                total.1 += bundle.uncompressed;
+                total.2 += bundle.compressed;
+            }
+            let mut by_ext = by_ext.into_iter().collect::<Vec<_>>();
+            by_ext.sort_by_key(|(_, (_, size, _))| std::cmp::Reverse(*size));
+            header("extension");
+            for (ext, values) in by_ext {
+                row(ext, values);
+            }
+
+            header("bundle");
+            if stats.len() > 1 {
+                for (i, bundle) in stats.iter() {
+                    row(&labels[*i], (bundle.resources.len(), bundle.uncompressed, bundle.compressed));
+                }
+            }
+            row("total", total);
+
+            let mut largest = stats.iter()
+                .flat_map(|(i, bundle)| bundle.resources.iter().map(move |resource| (*i, resource)))
+                .collect::<Vec<_>>();
+            largest.sort_by_key(|(_, resource)| std::cmp::Reverse(resource.size));
+            if top > 0 && !largest.is_empty() {
+                println!();
+                println!(" {:>14} {:>14}   {:<26} name", "size", "compressed", "bundle");
+                for (i, resource) in largest.into_iter().take(top) {
+                    let (name_hash, ext_hash) = resource.file;
+                    let ext = hash::extension_lookup(ext_hash).unwrap_or("?");
+                    print!(" {:>14} {:>14}   {:<26} ", resource.size, resource.compressed, labels[i]);
+                    if let Some(dict) = &dictionary
+                        && let Some(name) = dict.get(&MurmurHash64::from_u64(name_hash))
+                    {
+                        println!("{name}.{ext}");
+                    } else {
+                        println!("{name_hash:016x}.{ext}");
+                    }
+                }
+            }
+        } else if app.subcmd(&FSCK) {
+            let mut worst = None;
+            for bundle in params {
+                let name = Path::new(bundle).display();
+                let problems = match fsck::check(bundle) {
+                    Ok(problems) => problems,
+                    Err(e) => {
+                        println!("{name}: error: {e}");
+                        worst = Some(fsck::Severity::Error);
+                        continue;
+                    }
+                };
+                if problems.is_empty() {
+                    println!("{name}: ok");
+                }
+                for problem in problems {
+                    println!("{name}: {}: {}", problem.severity, problem.message);
+                    worst = worst.max(Some(problem.severity));
+                }
+            }
+            std::process::exit(match worst {
+                Some(fsck::Severity::Error) => 2,
+                Some(fsck::Severity::Warning) => 1,
+                _ => 0,
+            });
+        } else if app.subcmd(&DATABASE) {
+            let path = Path::new(params.next().expect("failed to parse parameter database"));
+            let database = database::Database::read(path).unwrap();
+            for bundle in database.bundles.iter() {
+                let resources = database.contents(bundle.hash);
+                println!("{:016x}   {} files   {} resources", bundle.hash, bundle.files.len(), resources.len());
+                for file in bundle.files.iter() {
+                    println!("    file {}   stream {}   time {}{}", file.name, file.stream, file.file_time,
+                        if file.platform_specific { "   platform specific" } else { "" });
+                }
+                for (ext_hash, name_hash) in resources.iter() {
+                    let ext = hash::extension_lookup(*ext_hash)
+                        .map(|ext| ext.to_string())
+                        .unwrap_or_else(|| format!("{ext_hash:016x}"));
+                    if let Some(dict) = &dictionary
+                        && let Some(name) = dict.get(&MurmurHash64::from_u64(*name_hash))
+                    {
+                        println!("    {name}.{ext}");
+                    } else {
+                        println!("    {name_hash:016x}.{ext}");
+                    }
+                }
+                println!();
+            }
+            println!("{} resource hashes", database.resource_hashes.len());
+        } else if app.subcmd(&CHAIN) {
+            let bundle = params.next().expect("failed to parse parameter bundle");
+            if let Some(dir) = params.next() {
+                let mut chain = bundle::Merge::new(&settings);
+                chain.unpack_chain_from(bundle).unwrap();
+                chain.unpack_to_dir(dir).unwrap();
+            } else {
+                let chain = patch::find_chain(bundle).unwrap();
+                let (entries, deleted) = patch::effective_index(&chain).unwrap();
+                let name = |entry: &patch::Entry| {
+                    let ext = hash::extension_lookup(entry.ext_hash)
+                        .map(|ext| ext.to_string())
+                        .unwrap_or_else(|| format!("{:016x}", entry.ext_hash));
+                    if let Some(dict) = &dictionary
+                        && let Some(name) = dict.get(&MurmurHash64::from_u64(entry.name_hash))
+                    {
+                        format!("{name}.{ext}")
+                    } else {
+                        format!("{:016x}.{ext}", entry.name_hash)
+                    }
+                };
+                let source = |entry: &patch::Entry| {
+                    chain[entry.source].file_name().unwrap().to_string_lossy().into_owned()
+                };
+
+                for entry in entries.iter() {
+                    println!("{:<26}   {}", source(entry), name(entry));
+                }
+                for entry in deleted.iter() {
+                    println!("{:<26}   {} (deleted)", source(entry), name(entry));
+                }
+                eprintln!("{} bundles, {} resources, {} deleted", chain.len(), entries.len(), deleted.len());
+            }
+        } else if app.subcmd(&DIFF) {
+            let a = params.next().expect("failed to parse parameter a");
+            let b = params.next().expect("failed to parse parameter b");
+            let mut merge_a = bundle::Merge::new(&settings);
+            merge_a.unpack_from(a).unwrap();
+            let mut merge_b = bundle::Merge::new(&settings);
+            merge_b.unpack_from(b).unwrap();
+
+            let bytes = app.switch_active(&BYTES);
+            let changes = diff::diff(&merge_a.files, &merge_b.files);
+            let (mut added, mut removed, mut modified) = (0, 0, 0);
+            for entry in changes.iter() {
+                let (name_hash, ext_hash) = entry.file;
+                let ext = hash::extension_lookup(ext_hash)
+                    .map(|ext| ext.to_string())
+                    .unwrap_or_else(|| format!("{ext_hash:016x}"));
+                let name = if let Some(dict) = &dictionary
+                    && let Some(name) = dict.get(&MurmurHash64::from_u64(name_hash))
+                {
+                    format!("{name}.{ext}")
+                } else {
+                    format!("{name_hash:016x}.{ext}")
+                };
+
+                match (&entry.change, entry.size) {
+                    (diff::Change::Added, (_, Some(size))) => {
+                        added += 1;
+                        println!("+ {name}   {size}");
+                    }
+                    (diff::Change::Removed, (Some(size), _)) => {
+                        removed += 1;
+                        println!("- {name}   {size}");
+                    }
+                    (diff::Change::Modified, (Some(a_size), Some(b_size))) => {
+                        modified += 1;
+                        print!("~ {name}   {a_size} -> {b_size} ({:+})", b_size as i64 - a_size as i64);
+                        if entry.flags.0 != entry.flags.1 {
+                            print!("   flags {} -> {}", entry.flags.0.unwrap_or(0), entry.flags.1.unwrap_or(0));
+                        }
+                        println!();
+
+                        if bytes {
+                            let summary = diff::byte_summary(
+                                &merge_a.files[&entry.file],
+                                &merge_b.files[&entry.file],
+                            );
+                            if let Some(first) = summary.first_difference {
+                                println!("      first difference at 0x{first:x}, {} bytes differ in {} ranges",
+                                    summary.changed, summary.ranges);
+                            }
+                        }
+                    }
+                    _ => unreachable!(),
+                }
+            }
+            eprintln!("{added} added, {removed} removed, {modified} modified");
+        } else if app.subcmd(&DELTA) {
+            let base = params.next().expect("failed to parse parameter base");
+            let edited = params.next().expect("failed to parse parameter edited");
+            let out = params.next().expect("failed to parse parameter out");
+            let tombstone = app.switch_params(DELETED)
+                .and_then(|mut params| params.next())
+                .map(deleted_flags);
+
+            let mut merge_base = bundle::Merge::new(&settings);
+            merge_base.unpack_from(base).unwrap();
+            let mut merge_edited = bundle::Merge::new(&settings);
+            if Path::new(edited).is_dir() {
+                merge_edited.unpack_from_dir(edited).unwrap();
+            } else {
+                merge_edited.unpack_from(edited).unwrap();
+            }
+
+            let mut delta = bundle::Merge::new(&settings);
+            delta.header = merge_edited.header;
+            delta.layout = merge_edited.layout.clone();
+            delta.files = diff::delta(&merge_base.files, &merge_edited.files, tombstone);
+            eprintln!("{} of {} resources in delta", delta.files.len(), merge_edited.files.len());
+            delta.repack_to(out).unwrap();
+        } else if app.subcmd(&TOMBSTONE) {
+            let bundle = params.next().expect("failed to parse parameter bundle");
+            let flags = app.switch_params(DELETED_FLAGS)
+                .and_then(|mut params| params.next())
+                .map(deleted_flags)
+                .unwrap_or(0x01);
+
+            let mut merge = bundle::Merge::new(&settings);
+            merge.unpack_from(bundle).unwrap();
+            for file in params {
+                let file = file.to_string_lossy();
+                let (name_hash, ext_hash) = resource::parse_file_name(&file)
+                    .unwrap_or_else(|| panic!("\"{file}\" is not a name.ext with known extension"));
+                let added = !merge.files.contains_key(&(name_hash, ext_hash));
+                merge.files.insert((name_hash, ext_hash), resource::tombstone(ext_hash, name_hash, flags));
+                eprintln!("{} {name_hash:016x}.{}", if added { "added" } else { "marked" },
+                    hash::extension_lookup(ext_hash).unwrap());
+            }
+            merge.repack_in_place(bundle).unwrap();
+        } else if app.subcmd(&SET) {
+            let bundle = params.next().expect("failed to parse parameter bundle");
+            let name = params.next().expect("failed to parse parameter name.ext").to_string_lossy();
+            let file = params.next().expect("failed to parse parameter file");
+            let key = resource::parse_file_name(&name)
+                .unwrap_or_else(|| panic!("\"{name}\" is not a name.ext with known extension"));
+
+            let mut merge = bundle::Merge::new(&settings);
+            merge.unpack_from(bundle).unwrap();
+            let data = fs::read(file).unwrap();
+            let original = merge.files.get(&key).map(|data| &data[..]);
+            let data = convert::wrap_file(key, Path::new(file), &data, original).unwrap();
+            let replaced = merge.files.insert(key, data).is_some();
+            eprintln!("{} {:016x}.{}", if replaced { "replaced" } else { "added" },
+                key.0, hash::extension_lookup(key.1).unwrap());
+            merge.repack_in_place(bundle).unwrap();
+        } else if app.subcmd(&RM) {
+            let bundle = params.next().expect("failed to parse parameter bundle");
+
+            let mut merge = bundle::Merge::new(&settings);
+            merge.unpack_from(bundle).unwrap();
+            for name in params {
+                let name = name.to_string_lossy();
+                let key = resource::parse_file_name(&name)
+                    .unwrap_or_else(|| panic!("\"{name}\" is not a name.ext with known extension"));
+                if merge.files.remove(&key).is_none() {
+                    panic!("\"{name}\" is not in bundle");
+                }
+                eprintln!("removed {:016x}.{}", key.0, hash::extension_lookup(key.1).unwrap());
+            }
+            merge.repack_in_place(bundle).unwrap();
+        } else if app.subcmd(&EXTRACT) {
+            let bundle = params.next().expect("failed to parse parameter bundle");
+            let name = params.next().expect("failed to parse parameter name.ext").to_string_lossy();
+            let key = resource::parse_file_name(&name)
+                .unwrap_or_else(|| panic!("\"{name}\" is not a name.ext with known extension"));
+            let ext = hash::extension_lookup(key.1).unwrap();
+
+            let data = index::extract_resource(bundle, key)
+                .unwrap()
+                .unwrap_or_else(|| panic!("\"{name}\" is not in bundle"));
+            let data = if app.switch_active(&RAW) {
+                let resource = resource::Resource::parse(&data).unwrap();
+                resource.variants.first().map(|variant| variant.data.to_vec()).unwrap_or_default()
+            } else {
+                data
+            };
+
+            let out = match params.next() {
+                Some(out) => Path::new(out).to_path_buf(),
+                None => {
+                    let name = if let Some(dict) = &dictionary
+                        && let Some(name) = dict.get(&MurmurHash64::from_u64(key.0))
+                    {
+                        format!("{name}.{ext}")
+                    } else if let Some((stem, _)) = name.rsplit_once('.')
+                        && hash::stingray_hash64(stem.as_bytes()) == key.0
+                    {
+                        name.to_string()
+                    } else {
+                        format!("{:016x}.{ext}", key.0)
+                    };
+                    Path::new(&name).file_name().unwrap().into()
+                }
+            };
+            fs::write(&out, &data).unwrap();
+            eprintln!("extracted {:016x}.{ext} to \"{}\"", key.0, out.display());
+        } else if app.subcmd(&CAT) {
+            let bundle = params.next().expect("failed to parse parameter bundle");
+            let name = params.next().expect("failed to parse parameter name.ext").to_string_lossy();
+            let key = resource::parse_file_name(&name)
+                .unwrap_or_else(|| panic!("\"{name}\" is not a name.ext with known extension"));
+            let language = app.switch_params(LANGUAGE)
+                .and_then(|mut params| params.next())
+                .map(|id| {
+                    let id = id.to_string_lossy();
+                    match id.strip_prefix("0x") {
+                        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                        None => id.parse::<u32>().ok(),
+                    }.expect("failed to parse language ID")
+                });
+            // a single variant's payload has no index prefix to keep
+            assert!(language.is_none() || !app.switch_active(&PREFIX), "--prefix is not supported with --language");
+
+            let data = index::extract_resource(bundle, key)
+                .unwrap()
+                .unwrap_or_else(|| panic!("\"{name}\" is not in bundle"));
+            let mut stdout = io::stdout().lock();
+            if let Some(language) = language {
+                let resource = resource::Resource::parse(&data).unwrap();
+                let variant = resource.variant(language).unwrap_or_else(|| {
+                    let languages = resource.variants.iter()
+                        .map(|variant| format!("{:#x}", variant.language))
+                        .collect::<Vec<_>>();
+                    panic!("no variant for language {language:#x}, has {}", languages.join(", "))
+                });
+                stdout.write_all(variant.data).unwrap();
+            } else if app.switch_active(&PREFIX) {
+                stdout.write_all(&data).unwrap();
+            } else {
+                stdout.write_all(&data[resource::PREFIX_SIZE..]).unwrap();
+            }
         } else if app.subcmd(&HASH) {
             let string = params.next().expect("failed to parse parameter bundle").to_string_lossy();
             println!("{:16x}", hash::stingray_hash64(string.as_bytes()));
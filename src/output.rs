@@ -0,0 +1,186 @@
+/// Machine-readable listings for `index` and `scan`.
+///
+/// Every entry has the fields
+///
+///     bundle, format, ext_hash, ext, name_hash, name, flags, size, sha256
+///
+/// Hashes are written as hex strings, `ext`, `name`, `flags`, `size` and
+/// `sha256` (only computed with --content-hash) are empty (null in JSON)
+/// when unknown.
+use std::io;
+use std::io::Write;
+
+use crate::bundle::BundleFormat;
+use crate::json;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    Table,
+    Json,
+    Ndjson,
+    Csv,
+}
+
+impl Format {
+    pub fn parse(s: &str) -> Option<Self> {
+        Some(match s {
+            "table" => Format::Table,
+            "json" => Format::Json,
+            "ndjson" => Format::Ndjson,
+            "csv" => Format::Csv,
+            _ => return None,
+        })
+    }
+}
+
+pub struct Entry<'a> {
+    pub bundle: &'a str,
+    pub format: BundleFormat,
+    pub ext_hash: u64,
+    pub name_hash: u64,
+    pub flags: Option<u32>,
+    pub size: Option<u32>,
+    pub ext: Option<&'a str>,
+    pub name: Option<&'a str>,
+    // hex SHA-256 of resource data
+    pub sha256: Option<&'a str>,
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+fn or_null<T: ToString>(value: Option<T>) -> String {
+    value.map(|value| value.to_string()).unwrap_or_else(|| "null".to_string())
+}
+
+// Write entry as a single line (a JSON object for Json and Ndjson).
+pub fn write_entry(format: Format, out: &mut Vec<u8>, entry: &Entry) {
+    let version = entry.format.version();
+    match format {
+        Format::Json | Format::Ndjson => {
+            writeln!(out,
+                "{{\"bundle\":{},\"format\":{version},\"ext_hash\":\"{:016x}\",\"ext\":{},\
+                \"name_hash\":\"{:016x}\",\"name\":{},\"flags\":{},\"size\":{},\"sha256\":{}}}",
+                json::quote(entry.bundle),
+                entry.ext_hash,
+                or_null(entry.ext.map(json::quote)),
+                entry.name_hash,
+                or_null(entry.name.map(json::quote)),
+                or_null(entry.flags),
+                or_null(entry.size),
+                or_null(entry.sha256.map(json::quote)),
+            ).unwrap();
+        }
+        Format::Csv => {
+            writeln!(out, "{},{version},{:016x},{},{:016x},{},{},{},{}",
+                csv_field(entry.bundle),
+                entry.ext_hash,
+                entry.ext.map(csv_field).unwrap_or_default(),
+                entry.name_hash,
+                entry.name.map(csv_field).unwrap_or_default(),
+                entry.flags.map(|flags| flags.to_string()).unwrap_or_default(),
+                entry.size.map(|size| size.to_string()).unwrap_or_default(),
+                entry.sha256.unwrap_or_default(),
+            ).unwrap();
+        }
+        Format::Table => unreachable!("tables are printed by their commands"),
+    }
+}
+
+// Joins lines from write_entry into the complete listing.
+pub struct Writer<W: Write> {
+    format: Format,
+    out: W,
+    count: usize,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(format: Format, mut out: W) -> io::Result<Self> {
+        match format {
+            Format::Json => writeln!(out, "[")?,
+            Format::Csv => writeln!(out, "bundle,format,ext_hash,ext,name_hash,name,flags,size,sha256")?,
+            _ => (),
+        }
+        Ok(Self {
+            format,
+            out,
+            count: 0,
+        })
+    }
+
+    pub fn write_lines(&mut self, lines: &[u8]) -> io::Result<()> {
+        if self.format != Format::Json {
+            self.count += lines.iter().filter(|c| **c == b'\n').count();
+            return self.out.write_all(lines);
+        }
+
+        for line in lines.split(|c| *c == b'\n').filter(|line| !line.is_empty()) {
+            if self.count > 0 {
+                self.out.write_all(b",\n")?;
+            }
+            self.out.write_all(b"  ")?;
+            self.out.write_all(line)?;
+            self.count += 1;
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        if self.format == Format::Json {
+            if self.count > 0 {
+                writeln!(self.out)?;
+            }
+            writeln!(self.out, "]")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listing(format: Format) -> String {
+        let mut lines = Vec::new();
+        for name in [Some("a,\"b\""), None] {
+            write_entry(format, &mut lines, &Entry {
+                bundle: "0123456789abcdef",
+                format: BundleFormat::Five,
+                ext_hash: 0xa14e8dfa2cd117e2,
+                name_hash: 1,
+                flags: Some(0),
+                size: None,
+                ext: Some("lua"),
+                name,
+                sha256: None,
+            });
+        }
+        let mut out = Vec::new();
+        let mut writer = Writer::new(format, &mut out).unwrap();
+        writer.write_lines(&lines).unwrap();
+        writer.finish().unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn json_is_valid() {
+        let out = listing(Format::Json);
+        let value = json::parse(out.as_bytes()).unwrap();
+        let entries = value.as_array().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].get("name").and_then(|n| n.as_str()), Some("a,\"b\""));
+        assert_eq!(entries[1].get("format").and_then(|n| n.as_u64()), Some(5));
+    }
+
+    #[test]
+    fn csv_quotes() {
+        let out = listing(Format::Csv);
+        let line = out.lines().nth(1).unwrap();
+        assert_eq!(line, "0123456789abcdef,5,a14e8dfa2cd117e2,lua,0000000000000001,\"a,\"\"b\"\"\",0,,");
+    }
+}
@@ -0,0 +1,144 @@
+/// Patch bundle chains.
+///
+/// Vermintide 1 patches bundles by shipping `<bundle>.patch_001`,
+/// `<bundle>.patch_002`, ... next to the original. Patches are applied in
+/// order with later entries replacing earlier ones, and entries flagged as
+/// deleted removing the resource.
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::index;
+use crate::resource;
+
+pub struct Entry {
+    pub ext_hash: u64,
+    pub name_hash: u64,
+    pub flags: Option<u32>,
+    pub size: Option<u32>,
+    // index of bundle in chain that provided the entry
+    pub source: usize,
+}
+
+fn patch_number(path: &Path) -> Option<u32> {
+    let ext = path.extension()?.to_str()?;
+    ext.strip_prefix("patch_")?.parse().ok()
+}
+
+// Base bundle followed by its patch bundles in the order they apply.
+pub fn find_chain<P: AsRef<Path>>(base: P) -> io::Result<Vec<PathBuf>> {
+    let base = base.as_ref();
+    let base = match patch_number(base) {
+        Some(_) => base.with_extension(""),
+        None => base.to_path_buf(),
+    };
+    let name = base.file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid bundle path"))?
+        .to_os_string();
+    let dir = base.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+
+    let mut patches = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.file_stem() == Some(&name)
+            && let Some(number) = patch_number(&path)
+            && path.is_file()
+        {
+            patches.push((number, path));
+        }
+    }
+    patches.sort_unstable();
+
+    let mut chain = Vec::with_capacity(patches.len() + 1);
+    chain.push(base);
+    chain.extend(patches.into_iter().map(|(_, path)| path));
+    Ok(chain)
+}
+
+// Resources the game sees after applying the chain, and the entries removed
+// by deletions.
+pub fn effective_index(chain: &[PathBuf]) -> io::Result<(Vec<Entry>, Vec<Entry>)> {
+    let mut entries = Vec::new();
+    let mut lookup = HashMap::new();
+    for (source, bundle) in chain.iter().enumerate() {
+        for (ext_hash, name_hash, flags, size) in index::extract_index(bundle)? {
+            let entry = Entry {
+                ext_hash,
+                name_hash,
+                flags,
+                size,
+                source,
+            };
+            match lookup.get(&(name_hash, ext_hash)) {
+                Some(&i) => entries[i] = entry,
+                None => {
+                    lookup.insert((name_hash, ext_hash), entries.len());
+                    entries.push(entry);
+                }
+            }
+        }
+    }
+
+    Ok(entries.into_iter().partition(|entry| !entry.flags.map(resource::is_deleted).unwrap_or(false)))
+}
+
+#[cfg(test)]
+mod tests {
+    use byteorder::WriteBytesExt;
+    use byteorder::LE;
+    use super::*;
+
+    const LUA: u64 = 0xa14e8dfa2cd117e2;
+
+    // Format 6 bundle holding only an index of (name_hash, flags, size)
+    // entries, in a single stored chunk.
+    fn write_bundle(path: &Path, entries: &[(u64, u32, u32)]) {
+        let mut data = Vec::new();
+        data.write_u32::<LE>(entries.len() as u32).unwrap();
+        data.extend([0; 256]);
+        for (name_hash, flags, size) in entries {
+            data.write_u64::<LE>(LUA).unwrap();
+            data.write_u64::<LE>(*name_hash).unwrap();
+            data.write_u32::<LE>(*flags).unwrap();
+            data.write_u32::<LE>(*size).unwrap();
+        }
+        let mut bundle = Vec::new();
+        bundle.write_u16::<LE>(6).unwrap();
+        bundle.write_u16::<LE>(0xf000).unwrap();
+        bundle.write_u32::<LE>(data.len() as u32).unwrap();
+        bundle.write_u32::<LE>(0).unwrap();
+        data.resize(0x10000, 0);
+        bundle.write_u32::<LE>(0x10000).unwrap();
+        bundle.extend(data);
+        fs::write(path, bundle).unwrap();
+    }
+
+    #[test]
+    fn chain_overrides_and_deletes() {
+        let dir = std::env::temp_dir().join(format!("srpack-patch-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let base = dir.join("0123456789abcdef");
+        write_bundle(&base, &[(1, 0, 10), (2, 0, 20), (3, 0, 30)]);
+        write_bundle(&dir.join("0123456789abcdef.patch_002"), &[(1, 0, 12), (3, 1, 0)]);
+        write_bundle(&dir.join("0123456789abcdef.patch_001"), &[(1, 0, 11), (2, 0, 21), (4, 0, 41)]);
+        write_bundle(&dir.join("fedcba9876543210.patch_001"), &[(5, 0, 50)]);
+
+        // the chain is the same when starting from one of its patches
+        for start in [base.clone(), dir.join("0123456789abcdef.patch_002")] {
+            let chain = find_chain(start).unwrap();
+            let names = chain.iter().map(|path| path.file_name().unwrap().to_str().unwrap()).collect::<Vec<_>>();
+            assert_eq!(names, vec!["0123456789abcdef", "0123456789abcdef.patch_001", "0123456789abcdef.patch_002"]);
+        }
+
+        let (entries, deleted) = effective_index(&find_chain(&base).unwrap()).unwrap();
+        let entries = entries.iter().map(|entry| (entry.name_hash, entry.size, entry.source)).collect::<Vec<_>>();
+        // later patches win, first seen order is kept
+        assert_eq!(entries, vec![(1, Some(12), 2), (2, Some(21), 1), (4, Some(41), 1)]);
+        assert_eq!(deleted.len(), 1);
+        assert_eq!((deleted[0].name_hash, deleted[0].flags, deleted[0].source), (3, Some(1), 2));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
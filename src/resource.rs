@@ -0,0 +1,167 @@
+use std::io;
+use byteorder::ReadBytesExt;
+use byteorder::WriteBytesExt;
+use byteorder::LE;
+
+use crate::hash;
+
+// srpack's index header packed at the start of every unpacked file:
+// ext_hash (u64), name_hash (u64), flags (u32), size (u32)
+pub const PREFIX_SIZE: usize = 24;
+
+pub struct Variant<'a> {
+    pub language: u32,
+    pub stream_size: u32,
+    pub data: &'a [u8],
+}
+
+// Resource as stored by srpack (index prefix + resource data from bundle).
+pub struct Resource<'a> {
+    pub ext_hash: u64,
+    pub name_hash: u64,
+    pub flags: u32,
+    pub stream_offset: u32,
+    pub variants: Vec<Variant<'a>>,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+impl<'a> Resource<'a> {
+    pub fn parse(data: &'a [u8]) -> io::Result<Self> {
+        if data.len() < PREFIX_SIZE + 24 {
+            return Err(invalid("resource too small"));
+        }
+
+        let mut prefix = &data[..PREFIX_SIZE];
+        let ext_hash = prefix.read_u64::<LE>()?;
+        let name_hash = prefix.read_u64::<LE>()?;
+        let flags = prefix.read_u32::<LE>()?;
+
+        let mut rdr = &data[PREFIX_SIZE..];
+        if ext_hash != rdr.read_u64::<LE>()? || name_hash != rdr.read_u64::<LE>()? {
+            return Err(invalid("index prefix does not match resource header"));
+        }
+        let num_variants = rdr.read_u32::<LE>()? as usize;
+        let stream_offset = rdr.read_u32::<LE>()?;
+
+        let mut headers = Vec::with_capacity(num_variants.min(64));
+        for _ in 0..num_variants {
+            let language = rdr.read_u32::<LE>()?;
+            let len = rdr.read_u32::<LE>()? as usize;
+            let stream_size = rdr.read_u32::<LE>()?;
+            headers.push((language, len, stream_size));
+        }
+
+        let mut variants = Vec::with_capacity(headers.len());
+        for (language, len, stream_size) in headers {
+            if rdr.len() < len {
+                return Err(invalid("variant runs past end of resource"));
+            }
+            let data;
+            (data, rdr) = rdr.split_at(len);
+            variants.push(Variant {
+                language,
+                stream_size,
+                data,
+            });
+        }
+
+        Ok(Self {
+            ext_hash,
+            name_hash,
+            flags,
+            stream_offset,
+            variants,
+        })
+    }
+
+    pub fn variant(&self, language: u32) -> Option<&Variant<'a>> {
+        self.variants.iter().find(|v| v.language == language)
+    }
+
+    // Serialize into srpack's unpacked layout (index prefix + resource data).
+    pub fn to_vec(&self) -> Vec<u8> {
+        let len = self.variants.iter().map(|v| 12 + v.data.len()).sum::<usize>();
+        let mut out = Vec::with_capacity(PREFIX_SIZE + 24 + len);
+        let size = self.variants.first().map(|v| v.data.len()).unwrap_or(0);
+
+        out.write_u64::<LE>(self.ext_hash).unwrap();
+        out.write_u64::<LE>(self.name_hash).unwrap();
+        out.write_u32::<LE>(self.flags).unwrap();
+        out.write_u32::<LE>(u32::try_from(size).unwrap()).unwrap();
+
+        out.write_u64::<LE>(self.ext_hash).unwrap();
+        out.write_u64::<LE>(self.name_hash).unwrap();
+        out.write_u32::<LE>(u32::try_from(self.variants.len()).unwrap()).unwrap();
+        out.write_u32::<LE>(self.stream_offset).unwrap();
+        for variant in self.variants.iter() {
+            out.write_u32::<LE>(variant.language).unwrap();
+            out.write_u32::<LE>(u32::try_from(variant.data.len()).unwrap()).unwrap();
+            out.write_u32::<LE>(variant.stream_size).unwrap();
+        }
+        for variant in self.variants.iter() {
+            out.extend(variant.data);
+        }
+        out
+    }
+}
+
+// Index flags marking a resource as deleted (sorted to the end by repack).
+pub const fn is_deleted(flags: u32) -> bool {
+    flags == 0x01 || flags == 0x02
+}
+
+// Flags from srpack's index prefix.
+pub fn prefix_flags(data: &[u8]) -> Option<u32> {
+    data.get(16..20).map(|flags| u32::from_le_bytes(flags.try_into().unwrap()))
+}
+
+// Check if file data starts with srpack's index prefix for `ext_hash`.
+pub fn is_wrapped(data: &[u8], ext_hash: u64) -> bool {
+    data.len() >= PREFIX_SIZE && data[..8] == ext_hash.to_le_bytes()
+}
+
+// Parse `name.ext` as given on the command line. The name is either the name
+// hash (16 hex digits or 0x prefixed) or the resource path, which is hashed.
+pub fn parse_file_name(file: &str) -> Option<(u64, u64)> {
+    let (name, ext) = file.rsplit_once('.')?;
+    let ext_hash = hash::stingray_hash64(ext.as_bytes());
+    hash::extension_lookup(ext_hash)?;
+    let name_hash = if let Some(hex) = name.strip_prefix("0x") {
+        Some(u64::from_str_radix(hex, 16).ok()?)
+    } else if name.len() == 16 {
+        u64::from_str_radix(name, 16).ok()
+    } else {
+        None
+    }.unwrap_or_else(|| hash::stingray_hash64(name.as_bytes()));
+    Some((name_hash, ext_hash))
+}
+
+// Entry without data marking resource as deleted (`flags` 0x01 or 0x02).
+pub fn tombstone(ext_hash: u64, name_hash: u64, flags: u32) -> Vec<u8> {
+    debug_assert!(is_deleted(flags));
+    Resource {
+        ext_hash,
+        name_hash,
+        flags,
+        stream_offset: 0,
+        variants: Vec::new(),
+    }.to_vec()
+}
+
+// Build resource with a single variant around raw payload.
+pub fn wrap(ext_hash: u64, name_hash: u64, payload: &[u8]) -> Vec<u8> {
+    Resource {
+        ext_hash,
+        name_hash,
+        flags: 0,
+        stream_offset: 0,
+        variants: vec![Variant {
+            language: 0,
+            stream_size: 0,
+            data: payload,
+        }],
+    }.to_vec()
+}
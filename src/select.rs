@@ -0,0 +1,183 @@
+/// Resource selectors shared by `index`, `scan` and `unpack`.
+///
+///     --ext lua,package       extensions
+///     --name 'scripts/**'     globs against resolved names (`*` stays within
+///                             one path segment, `**` crosses segments)
+///     --hash 0x...,0x...      name hashes
+///     --flags deleted         deleted, live or a flags value
+///
+/// A resource is selected if it matches every given selector.
+use std::collections::HashMap;
+
+use crate::hash;
+use crate::hash::MurmurHash64;
+use crate::resource;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Flags {
+    Deleted,
+    Live,
+    Value(u32),
+}
+
+#[derive(Clone, Default)]
+pub struct Selector {
+    exts: Option<Vec<u64>>,
+    names: Option<Vec<String>>,
+    hashes: Option<Vec<u64>>,
+    flags: Option<Flags>,
+}
+
+fn list(s: &str) -> impl Iterator<Item = &str> {
+    s.split(',').map(|item| item.trim()).filter(|item| !item.is_empty())
+}
+
+impl Selector {
+    pub fn ext(&mut self, exts: &str) -> Result<(), String> {
+        let out = self.exts.get_or_insert_with(Vec::new);
+        for ext in list(exts) {
+            let ext_hash = hash::stingray_hash64(ext.as_bytes());
+            if hash::extension_lookup(ext_hash).is_none() {
+                return Err(format!("unknown extension \"{ext}\""));
+            }
+            out.push(ext_hash);
+        }
+        Ok(())
+    }
+
+    pub fn name(&mut self, patterns: &str) {
+        self.names.get_or_insert_with(Vec::new).extend(list(patterns).map(str::to_string));
+    }
+
+    pub fn hash(&mut self, hashes: &str) -> Result<(), String> {
+        let out = self.hashes.get_or_insert_with(Vec::new);
+        for hash in list(hashes) {
+            let hex = hash.strip_prefix("0x").unwrap_or(hash);
+            out.push(u64::from_str_radix(hex, 16).map_err(|_| format!("invalid hash \"{hash}\""))?);
+        }
+        Ok(())
+    }
+
+    pub fn flags(&mut self, flags: &str) -> Result<(), String> {
+        self.flags = Some(match flags {
+            "deleted" => Flags::Deleted,
+            "live" => Flags::Live,
+            flags => Flags::Value(flags.parse().map_err(|_| format!("invalid flags \"{flags}\""))?),
+        });
+        Ok(())
+    }
+
+    pub fn is_all(&self) -> bool {
+        self.exts.is_none() && self.names.is_none() && self.hashes.is_none() && self.flags.is_none()
+    }
+
+    // `flags` is None for bundles without flags in their index (format 4),
+    // which only match `live`.
+    pub fn matches(
+        &self,
+        ext_hash: u64,
+        name_hash: u64,
+        flags: Option<u32>,
+        dictionary: Option<&HashMap<MurmurHash64, String>>,
+    ) -> bool {
+        if let Some(exts) = &self.exts && !exts.contains(&ext_hash) {
+            return false;
+        }
+        if let Some(hashes) = &self.hashes && !hashes.contains(&name_hash) {
+            return false;
+        }
+        if let Some(select) = self.flags {
+            let flags = flags.unwrap_or(0);
+            let matched = match select {
+                Flags::Deleted => resource::is_deleted(flags),
+                Flags::Live => !resource::is_deleted(flags),
+                Flags::Value(value) => flags == value,
+            };
+            if !matched {
+                return false;
+            }
+        }
+        if let Some(patterns) = &self.names {
+            let resolved = dictionary.and_then(|dict| dict.get(&MurmurHash64::from_u64(name_hash)));
+            let hex = format!("{name_hash:016x}");
+            let matched = patterns.iter().any(|pattern| {
+                if !pattern.contains(['*', '?']) {
+                    // plain names match by hash, no dictionary needed
+                    hash::stingray_hash64(pattern.as_bytes()) == name_hash || *pattern == hex
+                } else {
+                    glob(pattern.as_bytes(), resolved.unwrap_or(&hex).as_bytes())
+                }
+            });
+            if !matched {
+                return false;
+            }
+        }
+        true
+    }
+
+    // Match against srpack's unpacked layout (index prefix + resource data).
+    pub fn matches_data(
+        &self,
+        data: &[u8],
+        dictionary: Option<&HashMap<MurmurHash64, String>>,
+    ) -> bool {
+        if data.len() < resource::PREFIX_SIZE {
+            return false;
+        }
+        let ext_hash = u64::from_le_bytes(data[..8].try_into().unwrap());
+        let name_hash = u64::from_le_bytes(data[8..16].try_into().unwrap());
+        self.matches(ext_hash, name_hash, resource::prefix_flags(data), dictionary)
+    }
+}
+
+// `*` and `?` don't match '/', `**` matches anything.
+fn glob(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        // `**/` also matches no directory at all
+        [b'*', b'*', b'/', rest @ ..] => {
+            glob(rest, text)
+                || text.iter().enumerate().any(|(i, c)| *c == b'/' && glob(rest, &text[i + 1..]))
+        }
+        [b'*', b'*', rest @ ..] => (0..=text.len()).any(|i| glob(rest, &text[i..])),
+        [b'*', rest @ ..] => {
+            let end = text.iter().position(|c| *c == b'/').unwrap_or(text.len());
+            (0..=end).any(|i| glob(rest, &text[i..]))
+        }
+        [b'?', rest @ ..] => matches!(text.first(), Some(c) if *c != b'/') && glob(rest, &text[1..]),
+        [c, rest @ ..] => text.first() == Some(c) && glob(rest, &text[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_segments() {
+        assert!(glob(b"scripts/**", b"scripts/ui/hud.lua"));
+        assert!(glob(b"scripts/*", b"scripts/hud"));
+        assert!(!glob(b"scripts/*", b"scripts/ui/hud"));
+        assert!(glob(b"**/hud", b"scripts/ui/hud"));
+        assert!(glob(b"**/hud", b"hud"));
+        assert!(glob(b"scripts/**/hud", b"scripts/hud"));
+        assert!(glob(b"units/?at", b"units/cat"));
+        assert!(!glob(b"units/?at", b"units/at"));
+    }
+
+    #[test]
+    fn select_all_given() {
+        let mut select = Selector::default();
+        select.ext("lua").unwrap();
+        select.name("scripts/**");
+        let name = "scripts/hud";
+        let name_hash = hash::stingray_hash64(name.as_bytes());
+        let mut dict = HashMap::new();
+        dict.insert(MurmurHash64::from_u64(name_hash), name.to_string());
+
+        let lua = hash::stingray_hash64(b"lua");
+        assert!(select.matches(lua, name_hash, Some(0), Some(&dict)));
+        assert!(!select.matches(lua, name_hash, Some(0), None));
+        assert!(!select.matches(hash::stingray_hash64(b"package"), name_hash, Some(0), Some(&dict)));
+    }
+}